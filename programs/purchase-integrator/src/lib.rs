@@ -0,0 +1,105 @@
+// Anchor 0.31's generated IDL instructions still call the deprecated `AccountInfo::realloc`.
+#![allow(deprecated)]
+
+use anchor_lang::prelude::*;
+
+use solana_ico::cpi::accounts::PurchaseTokens;
+use solana_ico::program::IcoTokenSale;
+
+declare_id!("Hg7pfvzmxvhJH7n8jyUq333ng3v9uWbNp8Hnt3cvVsRa");
+
+#[program]
+pub mod purchase_integrator {
+    use super::*;
+
+    /// Buy through `purchase_tokens` over CPI and relay the receipt it returns
+    pub fn buy(ctx: Context<Buy>, token_amount: u64) -> Result<()> {
+        let accounts = PurchaseTokens {
+            sale: ctx.accounts.sale.to_account_info(),
+            user_purchase: ctx.accounts.user_purchase.to_account_info(),
+            buyer: ctx.accounts.buyer.to_account_info(),
+            token_mint: ctx.accounts.token_mint.to_account_info(),
+            sale_token_vault: ctx.accounts.sale_token_vault.to_account_info(),
+            buyer_token_account: ctx.accounts.buyer_token_account.to_account_info(),
+            treasury: ctx.accounts.treasury.to_account_info(),
+            stake_position: None,
+            stake_token_account: None,
+            aggregate_config: None,
+            aggregate_limit: None,
+            referrer: None,
+            referral: None,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+        };
+
+        let receipt = solana_ico::cpi::purchase_tokens(
+            CpiContext::new(ctx.accounts.ico_program.to_account_info(), accounts),
+            token_amount,
+            false,
+            vec![],
+            None,
+            0,
+            None,
+        )?
+        .get();
+
+        emit!(ReceiptRelayed {
+            tokens_delivered: receipt.tokens_delivered,
+            sol_cost: receipt.sol_cost,
+            price_used: receipt.price_used,
+            user_total: receipt.user_total,
+        });
+
+        Ok(())
+    }
+}
+
+/// Accounts are validated by the sale program
+#[derive(Accounts)]
+pub struct Buy<'info> {
+    /// CHECK: Validated by the sale program
+    #[account(mut)]
+    pub sale: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the sale program
+    #[account(mut)]
+    pub user_purchase: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    /// CHECK: Validated by the sale program
+    pub token_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the sale program
+    #[account(mut)]
+    pub sale_token_vault: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the sale program
+    #[account(mut)]
+    pub buyer_token_account: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the sale program
+    #[account(mut)]
+    pub treasury: UncheckedAccount<'info>,
+
+    pub ico_program: Program<'info, IcoTokenSale>,
+
+    /// CHECK: Validated by the sale program
+    pub token_program: UncheckedAccount<'info>,
+    /// CHECK: Validated by the sale program
+    pub associated_token_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: Validated by the sale program
+    pub rent: UncheckedAccount<'info>,
+}
+
+#[event]
+pub struct ReceiptRelayed {
+    pub tokens_delivered: u64,
+    pub sol_cost: u64,
+    pub price_used: u64,
+    pub user_total: u64,
+}
@@ -1,4 +1,13 @@
+// Anchor 0.31's generated IDL instructions still call the deprecated `AccountInfo::realloc`.
+#![allow(deprecated)]
+// `initialize_sale` takes every sale parameter, and the generated CPI client mirrors it.
+#![allow(clippy::too_many_arguments)]
+// `u64::is_multiple_of` needs Rust 1.87, newer than the platform tools' rustc.
+#![allow(clippy::manual_is_multiple_of)]
+
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::compute_units::sol_remaining_compute_units;
+use anchor_lang::solana_program::program_option::COption;
 
 use anchor_lang::system_program::{transfer, Transfer as SystemTransfer};
 use anchor_spl::{
@@ -6,8 +15,38 @@ use anchor_spl::{
     token::{self, Mint, Token, TokenAccount, Transfer},
 };
 
+mod math;
+mod merkle;
+
 declare_id!("GsShB9qNbSRFFDCZjr5zMFraTV3wWgbjuXQiiJ6AnVq4");
 
+/// Denominator for all basis-point parameters
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Decimals of `token_price_usd`, and the default `quote_decimals`
+pub const USD_PRICE_DECIMALS: u32 = 8;
+
+/// Default quote currency label of `token_price_usd`
+pub const DEFAULT_QUOTE_SYMBOL: &str = "USD";
+
+/// Longest quote currency label, in UTF-8 bytes
+pub const MAX_QUOTE_SYMBOL_LEN: usize = 8;
+
+/// Most decimals a quote currency can use
+pub const MAX_QUOTE_DECIMALS: u8 = 18;
+
+/// Maximum number of price tiers per sale
+pub const MAX_PRICE_TIERS: usize = 5;
+
+/// Maximum number of snapshot discount tiers per sale
+pub const MAX_DISCOUNT_TIERS: usize = 5;
+
+/// Maximum referral bonus, in basis points of the referred purchase
+pub const MAX_REFERRAL_BPS: u16 = 1_000;
+
+/// Compute units `process_refunds_batch` keeps in reserve before each refund
+pub const REFUND_BATCH_MIN_COMPUTE_UNITS: u64 = 25_000;
+
 #[program]
 pub mod ico_token_sale {
     use super::*;
@@ -15,140 +54,464 @@ pub mod ico_token_sale {
     /// Initialize the ICO sale with parameters
     pub fn initialize_sale(
         ctx: Context<InitializeSale>,
-        token_price: u64,   // Price per token in SOL (lamports)
-        max_tokens: u64,    // Maximum tokens to sell
-        min_purchase: u64,  // Minimum token purchase amount
-        max_purchase: u64,  // Maximum token purchase per wallet
+        token_price: u64, // Price per whole token (10^decimals base units) in lamports
+        max_tokens: u64,  // Maximum tokens to sell, in base units
+        min_purchase: u64, // Minimum token purchase amount, in base units
+        max_purchase: u64, // Maximum token purchase per wallet, in base units
         sale_duration: i64, // Sale duration in seconds
+        start_delay: i64, // Seconds from now until the sale opens
+        stake_bonus_bps: u16, // Bonus allocation for auto-staked purchases
+        stake_lock_duration: i64, // Staking lockup in seconds (0 disables auto-stake)
+        stake_multiplier_bps: u16, // Rewards multiplier recorded on each stake
+        soft_cap: u64,    // Tokens that must sell for the sale to succeed (0 disables)
+        tge_bps: u16,     // Share released when claims open (10000 = instant delivery)
+        cliff_seconds: i64, // Delay after the sale ends before claims open
+        vesting_duration_seconds: i64, // Linear vesting period for the rest after claims open
+        referral_bps: u16, // Referrer bonus on referred purchases (0 disables)
+        immutable: bool,  // Freeze every parameter for the life of the sale
+        max_purchase_per_tx: u64, // Per-transaction token cap, in base units (0 disables)
+        purchase_cooldown_seconds: i64, // Minimum seconds between a wallet's purchases
+        launch_guard_seconds: i64, // Window after start_time that halves max_purchase_per_tx
+        lot_size: u64,    // Purchases must be multiples of this, in base units (1 disables)
+        quote_symbol: Option<String>, // Quote currency of `token_price_usd` (default "USD")
+        quote_decimals: Option<u8>, // Display decimals of `token_price_usd` (default 8)
+        rounding: Option<RoundingPolicy>, // How fractional SOL costs are rounded (default CeilCost)
     ) -> Result<()> {
         let sale = &mut ctx.accounts.sale;
         let clock = Clock::get()?;
 
-        require!(token_price > 0, ErrorCode::InvalidPrice);
-        require!(max_tokens > 0, ErrorCode::InvalidAmount);
-        require!(
-            min_purchase > 0 && min_purchase <= max_purchase,
-            ErrorCode::InvalidPurchaseLimit
-        );
-        require!(sale_duration > 0, ErrorCode::InvalidDuration);
+        let params = InitSaleParams {
+            token_price,
+            max_tokens,
+            min_purchase,
+            max_purchase,
+            sale_duration,
+            start_delay,
+            stake_bonus_bps,
+            stake_lock_duration,
+            stake_multiplier_bps,
+            soft_cap,
+            tge_bps,
+            cliff_seconds,
+            vesting_duration_seconds,
+            referral_bps,
+            immutable,
+            max_purchase_per_tx,
+            purchase_cooldown_seconds,
+            launch_guard_seconds,
+            lot_size,
+            quote_symbol,
+            quote_decimals,
+            rounding,
+        };
+        params.validate(clock.unix_timestamp)?;
+        let (start_time, end_time) = params
+            .schedule(clock.unix_timestamp)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let (quote_symbol, quote_decimals) = params
+            .quote_currency()
+            .ok_or(ErrorCode::InvalidQuoteCurrency)?;
 
         sale.authority = ctx.accounts.authority.key();
+        sale.seed_authority = sale.authority;
+        sale.pending_authority = None;
         sale.token_mint = ctx.accounts.token_mint.key();
         sale.treasury = ctx.accounts.treasury.key();
         sale.token_price = token_price;
+        sale.token_decimals = ctx.accounts.token_mint.decimals;
         sale.max_tokens = max_tokens;
         sale.min_purchase = min_purchase;
         sale.max_purchase = max_purchase;
         sale.tokens_sold = 0;
         sale.total_raised = 0;
-        sale.start_time = clock.unix_timestamp;
-        sale.end_time = clock.unix_timestamp + sale_duration;
+        sale.start_time = start_time;
+        sale.end_time = end_time;
         sale.is_active = true;
         sale.is_paused = false;
+        sale.stake_bonus_bps = stake_bonus_bps;
+        sale.stake_lock_duration = stake_lock_duration;
+        sale.stake_multiplier_bps = stake_multiplier_bps;
+        sale.stake_bonus_allocated = 0;
+        sale.payment_mint = Pubkey::default();
+        sale.payment_treasury_ata = Pubkey::default();
+        sale.token_price_usd = 0;
+        sale.total_raised_usd = 0;
+        sale.soft_cap = soft_cap;
+        sale.outcome = SaleOutcome::Pending;
+        sale.treasury_bump = ctx.bumps.treasury;
+        sale.tge_bps = tge_bps;
+        sale.cliff_seconds = cliff_seconds;
+        sale.vesting_duration_seconds = vesting_duration_seconds;
+        sale.tokens_owed = 0;
+        sale.whitelist_root = [0; 32];
+        sale.whitelist_end_time = 0;
+        sale.whitelist_max_purchase = 0;
+        sale.tokens_reserved = 0;
+        sale.reservation_ttl_secs = 0;
+        sale.min_lamports_per_token = 0;
+        sale.enforce_aggregate_limit = false;
+        sale.tier_count = 0;
+        sale.tiers = [PriceTier::default(); MAX_PRICE_TIERS];
+        sale.referral_bps = referral_bps;
+        sale.referral_bonus_allocated = 0;
+        sale.referral_bonus_claimed = 0;
+        sale.immutable = immutable;
+        sale.max_purchase_per_tx = max_purchase_per_tx;
+        sale.purchase_cooldown_seconds = purchase_cooldown_seconds;
+        sale.launch_guard_seconds = launch_guard_seconds;
+        sale.refunds_paid = 0;
+        sale.treasury_withdrawn = 0;
+        sale.snapshot_root = [0; 32];
+        sale.discount_tier_count = 0;
+        sale.discount_tiers = [DiscountTier::default(); MAX_DISCOUNT_TIERS];
+        sale.lot_size = lot_size;
+        sale.quote_symbol = quote_symbol;
+        sale.quote_decimals = quote_decimals;
+        sale.rounding = params.rounding.unwrap_or_default();
         sale.bump = ctx.bumps.sale;
 
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.sale = sale.key();
+        treasury.bump = ctx.bumps.treasury;
+
         emit!(SaleInitialized {
             sale: sale.key(),
             authority: sale.authority,
             token_mint: sale.token_mint,
             token_price,
+            token_decimals: sale.token_decimals,
             max_tokens,
+            soft_cap,
             start_time: sale.start_time,
             end_time: sale.end_time,
+            immutable,
+            lot_size,
+            quote_symbol: sale.quote_symbol,
+            quote_decimals: sale.quote_decimals,
+            rounding: sale.rounding,
         });
 
         Ok(())
     }
 
-    /// Purchase tokens during the ICO
-    pub fn purchase_tokens(ctx: Context<PurchaseTokens>, token_amount: u64) -> Result<()> {
+    /// Dry-run `initialize_sale` (view). Runs the same parameter checks against the
+    /// same accounts without creating anything, and returns a bitmask of every
+    /// failed check (`InitSaleParams::*`); 0 means initialization would pass them.
+    pub fn validate_sale_params(
+        ctx: Context<ValidateSaleParams>,
+        params: InitSaleParams,
+    ) -> Result<u32> {
+        let mut failed = params.failed_checks(Clock::get()?.unix_timestamp);
+
+        // `init` would fail on accounts that are already in use
+        for (account, check) in [
+            (&ctx.accounts.sale, InitSaleParams::SALE_EXISTS),
+            (&ctx.accounts.treasury, InitSaleParams::TREASURY_EXISTS),
+        ] {
+            if !account.data_is_empty() || *account.owner != System::id() {
+                failed |= check;
+            }
+        }
+
+        if failed != 0 {
+            msg!("failed_checks={:#b}", failed);
+        }
+        Ok(failed)
+    }
+
+    /// Purchase tokens during the ICO, optionally staking them into a lockup for a bonus
+    /// and crediting a referrer. A non-zero `valid_until` rejects the purchase once the
+    /// clock has passed it, and a `snapshot` proof unlocks the holder discount.
+    pub fn purchase_tokens(
+        ctx: Context<PurchaseTokens>,
+        token_amount: u64,
+        auto_stake: bool,
+        proof: Vec<[u8; 32]>,
+        referrer: Option<Pubkey>,
+        valid_until: i64,
+        snapshot: Option<SnapshotProof>,
+    ) -> Result<PurchaseReceipt> {
+        ctx.accounts.process_purchase(
+            &ctx.bumps,
+            token_amount,
+            auto_stake,
+            &proof,
+            referrer,
+            valid_until,
+            snapshot.as_ref(),
+        )
+    }
+
+    /// Spend up to `sol_amount` lamports on as many tokens as it buys. Fills whatever
+    /// supply is left (charging only for it) instead of failing on `max_tokens`.
+    /// The amount is rounded down to whole lots, and only those are charged for.
+    pub fn purchase_with_sol(
+        ctx: Context<PurchaseTokens>,
+        sol_amount: u64,
+        min_tokens_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<PurchaseReceipt> {
+        let sale = &ctx.accounts.sale;
+
+        let supply_remaining = sale.max_tokens.saturating_sub(sale.tokens_committed()?);
+        let token_amount =
+            sale.round_down_to_lot(sale.tokens_for_sol(sol_amount)?.min(supply_remaining));
+        require!(token_amount >= min_tokens_out, ErrorCode::SlippageExceeded);
+
+        ctx.accounts
+            .process_purchase(&ctx.bumps, token_amount, false, &proof, None, 0, None)
+    }
+
+    /// Purchase tokens paying with the sale's SPL payment mint (e.g. USDC)
+    pub fn purchase_tokens_with_spl(
+        ctx: Context<PurchaseTokensWithSpl>,
+        token_amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
         let sale = &mut ctx.accounts.sale;
         let clock = Clock::get()?;
 
-        // Validate sale conditions
-        require!(sale.is_active, ErrorCode::SaleInactive);
-        require!(!sale.is_paused, ErrorCode::SalePaused);
-        require!(
-            clock.unix_timestamp >= sale.start_time && clock.unix_timestamp <= sale.end_time,
-            ErrorCode::SaleNotActive
-        );
-
-        // Validate purchase amount
-        require!(
-            token_amount >= sale.min_purchase,
-            ErrorCode::BelowMinimumPurchase
-        );
         require!(
-            token_amount <= sale.max_purchase,
-            ErrorCode::ExceedsMaximumPurchase
+            sale.payment_mint != Pubkey::default(),
+            ErrorCode::PaymentMintNotSet
         );
-        require!(
-            sale.tokens_sold + token_amount <= sale.max_tokens,
-            ErrorCode::ExceedsMaxTokens
-        );
-
-        // Calculate SOL cost
-        let sol_cost = token_amount
-            .checked_mul(sale.token_price)
-            .ok_or(ErrorCode::MathOverflow)?;
+        sale.assert_open(clock.unix_timestamp)?;
+        sale.assert_whitelisted(&ctx.accounts.buyer.key(), &proof, clock.unix_timestamp)?;
 
-        // Check user's purchase limit
         let user_purchase = &mut ctx.accounts.user_purchase;
-        require!(
-            user_purchase.tokens_purchased + token_amount <= sale.max_purchase,
-            ErrorCode::ExceedsUserLimit
-        );
+        sale.assert_cooldown_elapsed(user_purchase.last_purchase_ts, clock.unix_timestamp)?;
+        sale.assert_purchase_limits(
+            token_amount,
+            token_amount,
+            user_purchase.tokens_purchased,
+            clock.unix_timestamp,
+        )?;
+        if sale.enforce_aggregate_limit {
+            let config = ctx
+                .accounts
+                .aggregate_config
+                .as_ref()
+                .ok_or(ErrorCode::MissingAggregateAccounts)?;
+            ctx.accounts
+                .aggregate_limit
+                .as_mut()
+                .ok_or(ErrorCode::MissingAggregateAccounts)?
+                .record(
+                    ctx.accounts.buyer.key(),
+                    sale,
+                    token_amount,
+                    config.max_aggregate,
+                    user_purchase.tokens_purchased,
+                    clock.unix_timestamp,
+                    ctx.bumps
+                        .aggregate_limit
+                        .ok_or(ErrorCode::MissingAggregateAccounts)?,
+                )?;
+            user_purchase.aggregate_counted = user_purchase
+                .aggregate_counted
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
 
-        // Transfer SOL from buyer to treasury
-        let transfer_instruction = SystemTransfer {
-            from: ctx.accounts.buyer.to_account_info(),
-            to: ctx.accounts.treasury.to_account_info(),
-        };
+        let payment_amount =
+            sale.calculate_payment_cost(token_amount, ctx.accounts.payment_mint.decimals)?;
+        require!(payment_amount > 0, ErrorCode::ZeroCost);
 
-        transfer(
+        // Transfer payment tokens from buyer to the payment treasury
+        token::transfer(
             CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                transfer_instruction,
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.buyer_payment_account.to_account_info(),
+                    to: ctx.accounts.payment_treasury.to_account_info(),
+                    authority: ctx.accounts.buyer.to_account_info(),
+                },
             ),
-            sol_cost,
+            payment_amount,
+        )?;
+
+        if sale.delivers_instantly() {
+            // Transfer tokens from sale vault to buyer
+            let seeds = &[
+                b"sale",
+                sale.seed_authority.as_ref(),
+                sale.token_mint.as_ref(),
+                &[sale.bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.sale_token_vault.to_account_info(),
+                        to: ctx.accounts.buyer_token_account.to_account_info(),
+                        authority: sale.to_account_info(),
+                    },
+                    signer,
+                ),
+                token_amount,
+            )?;
+
+            user_purchase.tokens_claimed = user_purchase
+                .tokens_claimed
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            sale.tokens_owed = sale
+                .tokens_owed
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        // Update state
+        sale.tokens_sold = sale
+            .tokens_sold
+            .checked_add(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        sale.total_raised_usd = sale
+            .total_raised_usd
+            .checked_add(payment_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        user_purchase.user = ctx.accounts.buyer.key();
+        user_purchase.sale = sale.key();
+        user_purchase.bump = ctx.bumps.user_purchase;
+        user_purchase.record_purchase_time(clock.unix_timestamp)?;
+        user_purchase.tokens_purchased = user_purchase
+            .tokens_purchased
+            .checked_add(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        user_purchase.usd_contributed = user_purchase
+            .usd_contributed
+            .checked_add(payment_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(TokensPurchasedWithSpl {
+            buyer: ctx.accounts.buyer.key(),
+            payment_mint: sale.payment_mint,
+            token_amount,
+            payment_amount,
+            total_tokens_sold: sale.tokens_sold,
+            total_raised_usd: sale.total_raised_usd,
+        });
+
+        Ok(())
+    }
+
+    /// Purchase tokens for `buyer` with SOL paid by `sponsor`. Both sign; the buyer owns
+    /// the allocation and is held to the purchase limits, the sponsor pays cost and rent.
+    /// Takes the same arguments as `purchase_tokens`.
+    pub fn purchase_sponsored(
+        ctx: Context<PurchaseSponsored>,
+        token_amount: u64,
+        auto_stake: bool,
+        proof: Vec<[u8; 32]>,
+        referrer: Option<Pubkey>,
+        valid_until: i64,
+        snapshot: Option<SnapshotProof>,
+    ) -> Result<PurchaseReceipt> {
+        require_keys_neq!(
+            ctx.accounts.sponsor.key(),
+            ctx.accounts.buyer.key(),
+            ErrorCode::SelfSponsorship
+        );
+
+        let accounts = &mut *ctx.accounts;
+        let receipt = SolPurchase {
+            sale: &mut accounts.sale,
+            user_purchase: &mut accounts.user_purchase,
+            buyer: accounts.buyer.to_account_info(),
+            payer: accounts.sponsor.to_account_info(),
+            sale_token_vault: &accounts.sale_token_vault,
+            buyer_token_account: &accounts.buyer_token_account,
+            treasury: &accounts.treasury,
+            stake_position: &mut accounts.stake_position,
+            stake_token_account: &accounts.stake_token_account,
+            aggregate_config: &accounts.aggregate_config,
+            aggregate_limit: &mut accounts.aggregate_limit,
+            referrer: &accounts.referrer,
+            referral: &mut accounts.referral,
+            token_program: &accounts.token_program,
+            system_program: &accounts.system_program,
+            bumps: PurchaseBumps {
+                user_purchase: ctx.bumps.user_purchase,
+                stake_position: ctx.bumps.stake_position,
+                aggregate_limit: ctx.bumps.aggregate_limit,
+                referral: ctx.bumps.referral,
+            },
+        }
+        .process(
+            token_amount,
+            auto_stake,
+            &proof,
+            referrer,
+            valid_until,
+            snapshot.as_ref(),
         )?;
 
-        // Transfer tokens from sale vault to buyer
+        emit!(TokensPurchasedSponsored {
+            sponsor: accounts.sponsor.key(),
+        });
+
+        Ok(receipt)
+    }
+
+    /// Release staked tokens back to the owner once the lockup has expired
+    pub fn unstake(ctx: Context<Unstake>) -> Result<()> {
+        let stake = &ctx.accounts.stake_position;
+        let clock = Clock::get()?;
+
+        require!(
+            clock.unix_timestamp >= stake.unlock_time,
+            ErrorCode::StakeStillLocked
+        );
+        // A soft-cap sale may still fail, and `claim_refund` has to unwind the lockup
+        let sale = &ctx.accounts.sale;
+        require!(
+            sale.soft_cap == 0 || sale.outcome == SaleOutcome::Success,
+            ErrorCode::SaleNotSuccessful
+        );
+
+        let sale_key = stake.sale;
+        let owner_key = stake.owner;
         let seeds = &[
-            b"sale",
-            sale.authority.as_ref(),
-            sale.token_mint.as_ref(),
-            &[sale.bump],
+            b"stake",
+            sale_key.as_ref(),
+            owner_key.as_ref(),
+            &[stake.bump],
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.sale_token_vault.to_account_info(),
-            to: ctx.accounts.buyer_token_account.to_account_info(),
-            authority: sale.to_account_info(),
-        };
+        let amount = ctx.accounts.stake_token_account.amount;
 
         token::transfer(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
+                Transfer {
+                    from: ctx.accounts.stake_token_account.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: stake.to_account_info(),
+                },
                 signer,
             ),
-            token_amount,
+            amount,
         )?;
 
-        // Update state
-        sale.tokens_sold += token_amount;
-        sale.total_raised += sol_cost;
-        user_purchase.tokens_purchased += token_amount;
-        user_purchase.sol_contributed += sol_cost;
-
-        emit!(TokensPurchased {
-            buyer: ctx.accounts.buyer.key(),
-            token_amount,
-            sol_cost,
-            total_tokens_sold: sale.tokens_sold,
-            total_raised: sale.total_raised,
+        // Reclaim the lockup token account rent; the position itself is closed by Anchor
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::CloseAccount {
+                account: ctx.accounts.stake_token_account.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                authority: stake.to_account_info(),
+            },
+            signer,
+        ))?;
+
+        emit!(TokensUnstaked {
+            owner: owner_key,
+            sale: sale_key,
+            amount,
         });
 
         Ok(())
@@ -167,13 +530,59 @@ pub mod ico_token_sale {
         Ok(())
     }
 
+    /// Incident response in one transaction (authority only): pause the sale, then
+    /// emit its post-pause state with the treasury and vault balances. Unlike
+    /// `toggle_pause`, calling it again leaves the sale paused.
+    pub fn halt_and_report(ctx: Context<HaltAndReport>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        let was_paused = sale.is_paused;
+        sale.is_paused = true;
+        if !was_paused {
+            emit!(SaleToggled {
+                sale: sale.key(),
+                is_paused: true,
+            });
+        }
+
+        let treasury = ctx.accounts.treasury.to_account_info();
+        let rent_exempt = Rent::get()?.minimum_balance(treasury.data_len());
+        let treasury_balance = treasury.lamports().saturating_sub(rent_exempt);
+        let vault_balance = ctx.accounts.sale_token_vault.amount;
+        msg!(
+            "treasury_balance={} vault_balance={} tokens_owed={}",
+            treasury_balance,
+            vault_balance,
+            sale.tokens_owed
+        );
+
+        emit!(SaleHalted {
+            sale: sale.key(),
+            was_paused,
+            is_paused: sale.is_paused,
+            is_active: sale.is_active,
+            tokens_sold: sale.tokens_sold,
+            tokens_committed: sale.tokens_committed()?,
+            tokens_owed: sale.tokens_owed,
+            total_raised: sale.total_raised,
+            total_raised_usd: sale.total_raised_usd,
+            treasury_balance,
+            vault_balance,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
     /// End the sale early (authority only)
     pub fn end_sale(ctx: Context<EndSale>) -> Result<()> {
         let sale = &mut ctx.accounts.sale;
         let clock = Clock::get()?;
 
+        // Ending again must not push the end (and so the vesting cliff) later
         sale.is_active = false;
-        sale.end_time = clock.unix_timestamp;
+        sale.end_time = sale.end_time.min(clock.unix_timestamp);
 
         emit!(SaleEnded {
             sale: sale.key(),
@@ -185,22 +594,351 @@ pub mod ico_token_sale {
         Ok(())
     }
 
+    /// Settle the sale against its soft cap once it has ended (permissionless)
+    pub fn finalize_sale(ctx: Context<FinalizeSale>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            sale.has_ended(clock.unix_timestamp),
+            ErrorCode::SaleStillActive
+        );
+        require!(
+            sale.outcome == SaleOutcome::Pending,
+            ErrorCode::SaleAlreadyFinalized
+        );
+
+        sale.outcome = if sale.tokens_sold >= sale.soft_cap {
+            SaleOutcome::Success
+        } else {
+            SaleOutcome::Failed
+        };
+
+        emit!(SaleFinalized {
+            sale: sale.key(),
+            outcome: sale.outcome,
+            tokens_sold: sale.tokens_sold,
+            soft_cap: sale.soft_cap,
+            total_raised: sale.total_raised,
+        });
+
+        Ok(())
+    }
+
+    /// Return a buyer's SOL after a failed sale in exchange for their tokens
+    pub fn claim_refund(ctx: Context<ClaimRefund>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let user_purchase = &mut ctx.accounts.user_purchase;
+
+        require!(
+            sale.outcome == SaleOutcome::Failed,
+            ErrorCode::RefundNotAvailable
+        );
+        require!(!user_purchase.refunded, ErrorCode::AlreadyRefunded);
+
+        // Auto-staked tokens are still in the lockup; unwind it (bonus included) first.
+        // Unstaking waits for success, so a staked purchase always has its lockup here.
+        let mut staked_purchase = 0;
+        if user_purchase.tokens_staked > 0 {
+            let stake = ctx
+                .accounts
+                .stake_position
+                .as_ref()
+                .ok_or(ErrorCode::MissingStakeAccounts)?;
+            let stake_token_account = ctx
+                .accounts
+                .stake_token_account
+                .as_ref()
+                .ok_or(ErrorCode::MissingStakeAccounts)?;
+            let sale_key = sale.key();
+            let owner_key = ctx.accounts.buyer.key();
+            let seeds = &[
+                b"stake",
+                sale_key.as_ref(),
+                owner_key.as_ref(),
+                &[stake.bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: stake_token_account.to_account_info(),
+                        to: ctx.accounts.sale_token_vault.to_account_info(),
+                        authority: stake.to_account_info(),
+                    },
+                    signer,
+                ),
+                stake.amount,
+            )?;
+
+            token::close_account(CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::CloseAccount {
+                    account: stake_token_account.to_account_info(),
+                    destination: ctx.accounts.buyer.to_account_info(),
+                    authority: stake.to_account_info(),
+                },
+                signer,
+            ))?;
+            stake.close(ctx.accounts.buyer.to_account_info())?;
+
+            staked_purchase = stake
+                .amount
+                .checked_sub(stake.bonus_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+            sale.stake_bonus_allocated = sale
+                .stake_bonus_allocated
+                .checked_sub(stake.bonus_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+            user_purchase.tokens_staked = 0;
+        }
+
+        // Delivered tokens outside the lockup are in the buyer's wallet and must come back;
+        // undelivered (vesting) tokens never left the vault and are simply no longer owed
+        let wallet_tokens = user_purchase
+            .tokens_claimed
+            .checked_sub(staked_purchase)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let undelivered = user_purchase
+            .tokens_purchased
+            .checked_sub(user_purchase.tokens_claimed)
+            .ok_or(ErrorCode::MathOverflow)?;
+        if wallet_tokens > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.buyer_token_account.to_account_info(),
+                        to: ctx.accounts.sale_token_vault.to_account_info(),
+                        authority: ctx.accounts.buyer.to_account_info(),
+                    },
+                ),
+                wallet_tokens,
+            )?;
+        }
+
+        // Refunded tokens no longer count toward the wallet's cross-round cap
+        if user_purchase.aggregate_counted > 0 {
+            ctx.accounts
+                .aggregate_limit
+                .as_mut()
+                .ok_or(ErrorCode::MissingAggregateAccounts)?
+                .release(user_purchase.aggregate_counted);
+            user_purchase.aggregate_counted = 0;
+        }
+
+        let refund_amount = user_purchase.sol_contributed;
+        ctx.accounts.treasury.sub_lamports(refund_amount)?;
+        ctx.accounts.buyer.add_lamports(refund_amount)?;
+        sale.record_refund(undelivered, refund_amount)?;
+
+        user_purchase.refunded = true;
+
+        emit!(RefundClaimed {
+            sale: sale.key(),
+            buyer: ctx.accounts.buyer.key(),
+            tokens_returned: user_purchase.tokens_purchased,
+            sol_refunded: refund_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Refund a batch of buyers of a failed sale (anyone can call). `remaining_accounts`
+    /// holds (user_purchase, buyer) pairs, or (user_purchase, buyer, aggregate_limit)
+    /// triples while the sale enforces the aggregate limit. Only records whose tokens are all still in
+    /// the vault can be refunded without the buyer; records with delivered tokens are
+    /// skipped and left to `claim_refund`, as are ones already refunded. Stops early,
+    /// without failing, when compute runs low.
+    pub fn process_refunds_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ProcessRefundsBatch<'info>>,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+
+        require!(
+            sale.outcome == SaleOutcome::Failed,
+            ErrorCode::RefundNotAvailable
+        );
+        let group_len = if sale.enforce_aggregate_limit { 3 } else { 2 };
+        require!(
+            ctx.remaining_accounts.len() % group_len == 0,
+            ErrorCode::InvalidRefundBatch
+        );
+
+        let mut refunded: u32 = 0;
+        let mut already_refunded: u32 = 0;
+        let mut needs_buyer: u32 = 0;
+        let mut lamports_refunded: u64 = 0;
+        for group in ctx.remaining_accounts.chunks_exact(group_len) {
+            if sol_remaining_compute_units() < REFUND_BATCH_MIN_COMPUTE_UNITS {
+                break;
+            }
+            let (purchase_info, buyer_info) = (&group[0], &group[1]);
+
+            let mut user_purchase = Account::<UserPurchase>::try_from(purchase_info)?;
+            require!(
+                purchase_info.is_writable
+                    && buyer_info.is_writable
+                    && user_purchase.sale == sale.key()
+                    && user_purchase.user == buyer_info.key(),
+                ErrorCode::InvalidRefundBatch
+            );
+
+            if user_purchase.refunded {
+                already_refunded += 1;
+                continue;
+            }
+            // Delivered tokens have to come back from the buyer's wallet first, and
+            // without the aggregate account the counted tokens could not be released
+            if user_purchase.tokens_claimed > 0
+                || (user_purchase.aggregate_counted > 0 && group.len() < 3)
+            {
+                needs_buyer += 1;
+                continue;
+            }
+            if user_purchase.aggregate_counted > 0 {
+                let aggregate_info = &group[2];
+                let mut aggregate_limit = Account::<AggregateLimit>::try_from(aggregate_info)?;
+                require!(
+                    aggregate_info.is_writable
+                        && aggregate_limit.wallet == buyer_info.key()
+                        && aggregate_limit.mint == sale.token_mint,
+                    ErrorCode::InvalidRefundBatch
+                );
+                aggregate_limit.release(user_purchase.aggregate_counted);
+                aggregate_limit.exit(&crate::ID)?;
+                user_purchase.aggregate_counted = 0;
+            }
+
+            let refund_amount = user_purchase.sol_contributed;
+            ctx.accounts.treasury.sub_lamports(refund_amount)?;
+            buyer_info.add_lamports(refund_amount)?;
+            sale.record_refund(user_purchase.tokens_purchased, refund_amount)?;
+
+            user_purchase.refunded = true;
+            user_purchase.exit(&crate::ID)?;
+
+            emit!(RefundClaimed {
+                sale: sale.key(),
+                buyer: buyer_info.key(),
+                tokens_returned: user_purchase.tokens_purchased,
+                sol_refunded: refund_amount,
+            });
+
+            refunded += 1;
+            lamports_refunded = lamports_refunded
+                .checked_add(refund_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        // Groups left when compute ran out; crank them again in a later batch
+        let unprocessed = (ctx.remaining_accounts.len() / group_len) as u32
+            - (refunded + already_refunded + needs_buyer);
+        emit!(RefundBatchProcessed {
+            sale: sale.key(),
+            refunded,
+            already_refunded,
+            needs_buyer,
+            unprocessed,
+            lamports_refunded,
+        });
+
+        Ok(())
+    }
+
+    /// Claim vested tokens after the sale ends
+    pub fn claim_tokens(ctx: Context<ClaimTokens>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let user_purchase = &mut ctx.accounts.user_purchase;
+        let clock = Clock::get()?;
+
+        require!(
+            sale.has_ended(clock.unix_timestamp),
+            ErrorCode::SaleStillActive
+        );
+        // Soft-cap sales only deliver once they are known to have succeeded
+        require!(
+            sale.soft_cap == 0 || sale.outcome == SaleOutcome::Success,
+            ErrorCode::SaleNotSuccessful
+        );
+        require!(!user_purchase.refunded, ErrorCode::AlreadyRefunded);
+
+        let vested = sale.vested_amount(user_purchase.tokens_purchased, clock.unix_timestamp)?;
+        let claimable = vested.saturating_sub(user_purchase.tokens_claimed);
+        require!(claimable > 0, ErrorCode::NothingToClaim);
+
+        let seeds = &[
+            b"sale",
+            sale.seed_authority.as_ref(),
+            sale.token_mint.as_ref(),
+            &[sale.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.sale_token_vault.to_account_info(),
+                    to: ctx.accounts.buyer_token_account.to_account_info(),
+                    authority: sale.to_account_info(),
+                },
+                signer,
+            ),
+            claimable,
+        )?;
+
+        user_purchase.tokens_claimed = user_purchase
+            .tokens_claimed
+            .checked_add(claimable)
+            .ok_or(ErrorCode::MathOverflow)?;
+        sale.tokens_owed = sale
+            .tokens_owed
+            .checked_sub(claimable)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(TokensClaimed {
+            sale: sale.key(),
+            buyer: ctx.accounts.buyer.key(),
+            amount: claimable,
+            total_claimed: user_purchase.tokens_claimed,
+        });
+
+        Ok(())
+    }
+
     /// Withdraw remaining tokens after sale ends (authority only)
     pub fn withdraw_remaining_tokens(ctx: Context<WithdrawTokens>) -> Result<()> {
         let sale = &ctx.accounts.sale;
         let clock = Clock::get()?;
 
         require!(
-            !sale.is_active || clock.unix_timestamp > sale.end_time,
+            sale.has_ended(clock.unix_timestamp),
             ErrorCode::SaleStillActive
         );
 
-        let remaining_tokens = ctx.accounts.sale_token_vault.amount;
+        // Tokens bought under a vesting schedule but not yet claimed stay in the vault,
+        // as do referral bonuses unless the sale failed and they can never be claimed
+        let referral_bonus_owed = if sale.outcome == SaleOutcome::Failed {
+            0
+        } else {
+            sale.referral_bonus_allocated
+                .checked_sub(sale.referral_bonus_claimed)
+                .ok_or(ErrorCode::MathOverflow)?
+        };
+        let remaining_tokens = ctx
+            .accounts
+            .sale_token_vault
+            .amount
+            .saturating_sub(sale.tokens_owed)
+            .saturating_sub(referral_bonus_owed);
 
         if remaining_tokens > 0 {
             let seeds = &[
                 b"sale",
-                sale.authority.as_ref(),
+                sale.seed_authority.as_ref(),
                 sale.token_mint.as_ref(),
                 &[sale.bump],
             ];
@@ -237,6 +975,9 @@ pub mod ico_token_sale {
         new_max_tokens: Option<u64>,
         new_min_purchase: Option<u64>,
         new_max_purchase: Option<u64>,
+        new_max_purchase_per_tx: Option<u64>,
+        new_purchase_cooldown_seconds: Option<i64>,
+        new_launch_guard_seconds: Option<i64>,
     ) -> Result<()> {
         let sale = &mut ctx.accounts.sale;
         let clock = Clock::get()?;
@@ -266,17 +1007,588 @@ pub mod ico_token_sale {
             sale.max_purchase = max_purchase;
         }
 
+        if let Some(max_purchase_per_tx) = new_max_purchase_per_tx {
+            sale.max_purchase_per_tx = max_purchase_per_tx;
+        }
+
+        if let Some(cooldown) = new_purchase_cooldown_seconds {
+            require!(cooldown >= 0, ErrorCode::InvalidDuration);
+            sale.purchase_cooldown_seconds = cooldown;
+        }
+
+        if let Some(launch_guard) = new_launch_guard_seconds {
+            require!(launch_guard >= 0, ErrorCode::InvalidDuration);
+            sale.launch_guard_seconds = launch_guard;
+        }
+
         require!(
             sale.min_purchase <= sale.max_purchase,
             ErrorCode::InvalidPurchaseLimit
         );
-
-        emit!(SaleParamsUpdated {
-            sale: sale.key(),
+        require!(
+            sale.max_purchase_per_tx == 0 || sale.max_purchase_per_tx >= sale.min_purchase,
+            ErrorCode::InvalidPurchaseLimit
+        );
+        require!(
+            sale.whitelist_root == [0; 32] || sale.whitelist_max_purchase >= sale.min_purchase,
+            ErrorCode::InvalidPurchaseLimit
+        );
+        require!(sale.soft_cap <= sale.max_tokens, ErrorCode::InvalidSoftCap);
+        require!(
+            sale.min_purchase % sale.lot_size == 0
+                && sale.max_purchase % sale.lot_size == 0
+                && sale.max_purchase_per_tx % sale.lot_size == 0
+                && (sale.whitelist_root == [0; 32]
+                    || sale.whitelist_max_purchase % sale.lot_size == 0),
+            ErrorCode::InvalidLotSize
+        );
+        require!(
+            sale.tier_count == 0
+                || sale.tiers[sale.tier_count as usize - 1].token_cap_cumulative == sale.max_tokens,
+            ErrorCode::InvalidPriceTiers
+        );
+
+        emit!(SaleParamsUpdated {
+            sale: sale.key(),
             token_price: sale.token_price,
             max_tokens: sale.max_tokens,
             min_purchase: sale.min_purchase,
             max_purchase: sale.max_purchase,
+            max_purchase_per_tx: sale.max_purchase_per_tx,
+            purchase_cooldown_seconds: sale.purchase_cooldown_seconds,
+            launch_guard_seconds: sale.launch_guard_seconds,
+        });
+
+        Ok(())
+    }
+
+    /// Return a wallet's purchase totals and remaining allowance (view)
+    pub fn get_user_info(ctx: Context<GetUserInfo>) -> Result<UserInfo> {
+        let sale = &ctx.accounts.sale;
+        let (tokens_purchased, sol_contributed, usd_contributed) = match &ctx.accounts.user_purchase
+        {
+            Some(purchase) => (
+                purchase.tokens_purchased,
+                purchase.sol_contributed,
+                purchase.usd_contributed,
+            ),
+            None => (0, 0, 0),
+        };
+        let now = Clock::get()?.unix_timestamp;
+        let (remaining_allowance, _, _) = sale.remaining_allowance(tokens_purchased, now)?;
+
+        Ok(UserInfo {
+            tokens_purchased,
+            sol_contributed,
+            usd_contributed,
+            remaining_allowance,
+            sale_immutable: sale.immutable,
+            quote_symbol: sale.quote_symbol,
+            quote_decimals: sale.quote_decimals,
+        })
+    }
+
+    /// Account for every lamport the sale has raised (view). `drift` is what the
+    /// treasury holds beyond `total_raised - refunds_paid - treasury_withdrawn`.
+    pub fn reconcile(ctx: Context<Reconcile>) -> Result<Reconciliation> {
+        let sale = &ctx.accounts.sale;
+        let treasury = ctx.accounts.treasury.to_account_info();
+        let rent_exempt = Rent::get()?.minimum_balance(treasury.data_len());
+        let treasury_balance = treasury.lamports().saturating_sub(rent_exempt);
+
+        let accounted =
+            treasury_balance as i128 + sale.refunds_paid as i128 + sale.treasury_withdrawn as i128;
+        let drift = i64::try_from(accounted - sale.total_raised as i128)
+            .map_err(|_| error!(ErrorCode::MathOverflow))?;
+        if drift != 0 {
+            msg!("reconciliation drift={} lamports", drift);
+        }
+
+        Ok(Reconciliation {
+            total_raised: sale.total_raised,
+            refunds_paid: sale.refunds_paid,
+            treasury_withdrawn: sale.treasury_withdrawn,
+            treasury_balance,
+            drift,
+        })
+    }
+
+    /// Configure the SPL payment mint and its USD token price (authority only, before any SPL sales)
+    pub fn set_payment_mint(ctx: Context<SetPaymentMint>, token_price_usd: u64) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+
+        require!(token_price_usd > 0, ErrorCode::InvalidPrice);
+        require!(sale.total_raised_usd == 0, ErrorCode::PaymentMintLocked);
+        // SPL proceeds go straight to an external account and cannot be refunded
+        require!(sale.soft_cap == 0, ErrorCode::SoftCapRequiresSolPayments);
+        require!(sale.tier_count == 0, ErrorCode::TiersRequireSolPayments);
+
+        sale.payment_mint = ctx.accounts.payment_mint.key();
+        sale.payment_treasury_ata = ctx.accounts.payment_treasury.key();
+        sale.token_price_usd = token_price_usd;
+
+        emit!(PaymentMintSet {
+            sale: sale.key(),
+            payment_mint: sale.payment_mint,
+            payment_treasury_ata: sale.payment_treasury_ata,
+            token_price_usd,
+        });
+
+        Ok(())
+    }
+
+    /// Restrict the opening window to wallets in a Merkle allowlist (authority only, before sale starts).
+    /// A zero root disables the allowlist.
+    pub fn set_allowlist(
+        ctx: Context<SetAllowlist>,
+        root: [u8; 32],
+        whitelist_end_time: i64,
+        whitelist_max_purchase: u64,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            clock.unix_timestamp < sale.start_time,
+            ErrorCode::SaleAlreadyStarted
+        );
+        if root != [0; 32] {
+            require!(
+                whitelist_end_time > sale.start_time && whitelist_end_time <= sale.end_time,
+                ErrorCode::InvalidDuration
+            );
+            require!(
+                whitelist_max_purchase >= sale.min_purchase,
+                ErrorCode::InvalidPurchaseLimit
+            );
+            require!(
+                whitelist_max_purchase % sale.lot_size == 0,
+                ErrorCode::InvalidLotSize
+            );
+        }
+
+        sale.whitelist_root = root;
+        sale.whitelist_end_time = whitelist_end_time;
+        sale.whitelist_max_purchase = whitelist_max_purchase;
+
+        emit!(AllowlistSet {
+            sale: sale.key(),
+            root,
+            whitelist_end_time,
+            whitelist_max_purchase,
+        });
+
+        Ok(())
+    }
+
+    /// Set how long allocation holds last (authority only). 0 disables reservations.
+    pub fn set_reservation_ttl(
+        ctx: Context<SetReservationTtl>,
+        reservation_ttl_secs: i64,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+
+        require!(reservation_ttl_secs >= 0, ErrorCode::InvalidDuration);
+        sale.reservation_ttl_secs = reservation_ttl_secs;
+
+        emit!(ReservationTtlSet {
+            sale: sale.key(),
+            reservation_ttl_secs,
+        });
+
+        Ok(())
+    }
+
+    /// Replace the flat `token_price` with up to `MAX_PRICE_TIERS` tiers (authority only,
+    /// before sale starts). An empty list restores flat pricing.
+    pub fn set_tiers(ctx: Context<SetTiers>, tiers: Vec<PriceTier>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            clock.unix_timestamp < sale.start_time,
+            ErrorCode::SaleAlreadyStarted
+        );
+        require!(tiers.len() <= MAX_PRICE_TIERS, ErrorCode::InvalidPriceTiers);
+        // SPL purchases are priced by the flat `token_price_usd`
+        require!(
+            tiers.is_empty() || sale.payment_mint == Pubkey::default(),
+            ErrorCode::TiersRequireSolPayments
+        );
+
+        let mut previous_cap = 0;
+        for tier in &tiers {
+            require!(tier.price > 0, ErrorCode::InvalidPrice);
+            require!(
+                tier.token_cap_cumulative > previous_cap,
+                ErrorCode::InvalidPriceTiers
+            );
+            previous_cap = tier.token_cap_cumulative;
+        }
+        require!(
+            tiers.is_empty() || previous_cap == sale.max_tokens,
+            ErrorCode::InvalidPriceTiers
+        );
+
+        sale.tier_count = tiers.len() as u8;
+        sale.tiers = [PriceTier::default(); MAX_PRICE_TIERS];
+        sale.tiers[..tiers.len()].copy_from_slice(&tiers);
+
+        emit!(PriceTiersSet {
+            sale: sale.key(),
+            tiers,
+        });
+
+        Ok(())
+    }
+
+    /// Publish the Merkle root of a holder snapshot and the discount each snapshot
+    /// balance unlocks (authority only). A zero root disables the discount.
+    pub fn set_snapshot_root(
+        ctx: Context<SetSnapshotRoot>,
+        root: [u8; 32],
+        tiers: Vec<DiscountTier>,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+
+        require!(
+            tiers.len() <= MAX_DISCOUNT_TIERS,
+            ErrorCode::InvalidDiscountTiers
+        );
+        for (i, tier) in tiers.iter().enumerate() {
+            require!(
+                (tier.discount_bps as u64) < BPS_DENOMINATOR,
+                ErrorCode::InvalidDiscountTiers
+            );
+            if i > 0 {
+                require!(
+                    tier.min_balance > tiers[i - 1].min_balance,
+                    ErrorCode::InvalidDiscountTiers
+                );
+            }
+        }
+
+        sale.snapshot_root = root;
+        sale.discount_tier_count = tiers.len() as u8;
+        sale.discount_tiers = [DiscountTier::default(); MAX_DISCOUNT_TIERS];
+        sale.discount_tiers[..tiers.len()].copy_from_slice(&tiers);
+
+        emit!(SnapshotRootSet {
+            sale: sale.key(),
+            root,
+            tiers,
+        });
+
+        Ok(())
+    }
+
+    /// Set the minimum lamports the treasury must receive per whole token delivered
+    /// for SOL purchases (authority only). 0 disables the floor.
+    pub fn set_lamport_floor(
+        ctx: Context<SetLamportFloor>,
+        min_lamports_per_token: u64,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        sale.min_lamports_per_token = min_lamports_per_token;
+
+        emit!(LamportFloorSet {
+            sale: sale.key(),
+            min_lamports_per_token,
+        });
+
+        Ok(())
+    }
+
+    /// Move raised SOL out of the treasury PDA (authority only, once the sale has
+    /// ended and, with a soft cap, succeeded)
+    pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            sale.has_ended(clock.unix_timestamp),
+            ErrorCode::SaleStillActive
+        );
+        // Failed or unfinalized soft-cap sales keep SOL in the treasury for refunds
+        require!(
+            sale.soft_cap == 0 || sale.outcome == SaleOutcome::Success,
+            ErrorCode::SaleNotSuccessful
+        );
+
+        let treasury = ctx.accounts.treasury.to_account_info();
+        let rent_exempt = Rent::get()?.minimum_balance(treasury.data_len());
+        require!(
+            amount <= treasury.lamports().saturating_sub(rent_exempt),
+            ErrorCode::InsufficientTreasuryBalance
+        );
+
+        treasury.sub_lamports(amount)?;
+        ctx.accounts.destination.add_lamports(amount)?;
+        sale.treasury_withdrawn = sale
+            .treasury_withdrawn
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(TreasuryWithdrawn {
+            sale: sale.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Propose a new sale authority (authority only). Takes effect once accepted.
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        sale.pending_authority = Some(new_authority);
+
+        emit!(AuthorityProposed {
+            sale: sale.key(),
+            authority: sale.authority,
+            pending_authority: new_authority,
+        });
+
+        Ok(())
+    }
+
+    /// Complete a handover started by `propose_authority` (pending authority only)
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let new_authority = ctx.accounts.new_authority.key();
+
+        require!(
+            sale.pending_authority == Some(new_authority),
+            ErrorCode::NotPendingAuthority
+        );
+
+        let previous_authority = sale.authority;
+        sale.authority = new_authority;
+        sale.pending_authority = None;
+
+        emit!(AuthorityTransferred {
+            sale: sale.key(),
+            previous_authority,
+            new_authority,
+        });
+
+        Ok(())
+    }
+
+    /// Set the per-wallet cap on tokens bought across every sale of a mint that
+    /// enforces it (mint authority only). Mints whose authority has been revoked
+    /// cannot get or change a cap, so set it before revoking
+    pub fn set_aggregate_limit(ctx: Context<SetAggregateLimit>, max_aggregate: u64) -> Result<()> {
+        require!(max_aggregate > 0, ErrorCode::InvalidPurchaseLimit);
+
+        let config = &mut ctx.accounts.aggregate_config;
+        config.mint = ctx.accounts.token_mint.key();
+        config.max_aggregate = max_aggregate;
+        config.bump = ctx.bumps.aggregate_config;
+
+        emit!(AggregateLimitSet {
+            mint: config.mint,
+            max_aggregate,
+        });
+
+        Ok(())
+    }
+
+    /// Opt this sale in or out of the mint's aggregate limit (authority only)
+    pub fn set_enforce_aggregate_limit(
+        ctx: Context<SetEnforceAggregateLimit>,
+        enforce: bool,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        sale.enforce_aggregate_limit = enforce;
+
+        emit!(AggregateEnforcementSet {
+            sale: sale.key(),
+            enforce,
+        });
+
+        Ok(())
+    }
+
+    /// Hold part of the remaining supply for the buyer until the reservation expires
+    pub fn reserve_allocation(
+        ctx: Context<ReserveAllocation>,
+        token_amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            sale.reservation_ttl_secs > 0,
+            ErrorCode::ReservationsDisabled
+        );
+        sale.assert_open(clock.unix_timestamp)?;
+        sale.assert_whitelisted(&ctx.accounts.buyer.key(), &proof, clock.unix_timestamp)?;
+
+        let already_purchased = ctx
+            .accounts
+            .user_purchase
+            .as_ref()
+            .map_or(0, |purchase| purchase.tokens_purchased);
+        sale.assert_purchase_limits(
+            token_amount,
+            token_amount,
+            already_purchased,
+            clock.unix_timestamp,
+        )?;
+
+        sale.tokens_reserved = sale
+            .tokens_reserved
+            .checked_add(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let reservation = &mut ctx.accounts.reservation;
+        reservation.buyer = ctx.accounts.buyer.key();
+        reservation.sale = sale.key();
+        reservation.token_amount = token_amount;
+        reservation.expires_at = clock
+            .unix_timestamp
+            .checked_add(sale.reservation_ttl_secs)
+            .ok_or(ErrorCode::MathOverflow)?;
+        reservation.bump = ctx.bumps.reservation;
+
+        emit!(AllocationReserved {
+            sale: sale.key(),
+            buyer: reservation.buyer,
+            token_amount,
+            expires_at: reservation.expires_at,
+        });
+
+        Ok(())
+    }
+
+    /// Pay for an unexpired reservation with SOL, consuming it
+    pub fn purchase_reserved(
+        ctx: Context<PurchaseReserved>,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<PurchaseReceipt> {
+        let clock = Clock::get()?;
+        let token_amount = ctx.accounts.reservation.token_amount;
+
+        require!(
+            clock.unix_timestamp < ctx.accounts.reservation.expires_at,
+            ErrorCode::ReservationExpired
+        );
+
+        // Drop the hold first so the purchase's supply check doesn't count it twice
+        let sale = &mut ctx.accounts.purchase.sale;
+        sale.tokens_reserved = sale
+            .tokens_reserved
+            .checked_sub(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let receipt = ctx.accounts.purchase.process_purchase(
+            &ctx.bumps.purchase,
+            token_amount,
+            false,
+            &proof,
+            None,
+            0,
+            None,
+        )?;
+
+        let buyer = ctx.accounts.purchase.buyer.to_account_info();
+        ctx.accounts.reservation.close(buyer)?;
+
+        Ok(receipt)
+    }
+
+    /// Free a reservation's hold. The buyer can release it at any time; once it
+    /// has expired anyone can, so stale holds never strand supply.
+    pub fn release_reservation(ctx: Context<ReleaseReservation>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let reservation = &ctx.accounts.reservation;
+        let clock = Clock::get()?;
+
+        if ctx.accounts.caller.key() != reservation.buyer {
+            require!(
+                clock.unix_timestamp >= reservation.expires_at,
+                ErrorCode::ReservationNotExpired
+            );
+        }
+
+        sale.tokens_reserved = sale
+            .tokens_reserved
+            .checked_sub(reservation.token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(ReservationReleased {
+            sale: sale.key(),
+            buyer: reservation.buyer,
+            token_amount: reservation.token_amount,
+            released_by: ctx.accounts.caller.key(),
+        });
+
+        Ok(())
+    }
+
+    /// Claim the bonus a referrer earned on referred purchases, once the sale has ended
+    pub fn claim_referral_bonus(ctx: Context<ClaimReferralBonus>) -> Result<()> {
+        let sale = &mut ctx.accounts.sale;
+        let referral = &mut ctx.accounts.referral;
+        let clock = Clock::get()?;
+
+        require!(
+            sale.has_ended(clock.unix_timestamp),
+            ErrorCode::SaleStillActive
+        );
+        // Failed sales refund the referred purchases, so their bonuses are void
+        require!(
+            sale.soft_cap == 0 || sale.outcome == SaleOutcome::Success,
+            ErrorCode::SaleNotSuccessful
+        );
+
+        let claimable = referral
+            .bonus_tokens_earned
+            .checked_sub(referral.bonus_tokens_claimed)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(claimable > 0, ErrorCode::NothingToClaim);
+
+        // Buyers' unclaimed purchases come first; the bonus must fit in what is left
+        let available = ctx
+            .accounts
+            .sale_token_vault
+            .amount
+            .saturating_sub(sale.tokens_owed);
+        require!(claimable <= available, ErrorCode::InsufficientVaultBalance);
+
+        let seeds = &[
+            b"sale",
+            sale.seed_authority.as_ref(),
+            sale.token_mint.as_ref(),
+            &[sale.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.sale_token_vault.to_account_info(),
+                    to: ctx.accounts.referrer_token_account.to_account_info(),
+                    authority: sale.to_account_info(),
+                },
+                signer,
+            ),
+            claimable,
+        )?;
+
+        referral.bonus_tokens_claimed = referral.bonus_tokens_earned;
+        sale.referral_bonus_claimed = sale
+            .referral_bonus_claimed
+            .checked_add(claimable)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(ReferralBonusClaimed {
+            sale: sale.key(),
+            referrer: referral.referrer,
+            amount: claimable,
         });
 
         Ok(())
@@ -284,50 +1596,1229 @@ pub mod ico_token_sale {
 }
 
 #[derive(Accounts)]
-pub struct InitializeSale<'info> {
+pub struct InitializeSale<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Sale::INIT_SPACE,
+        seeds = [b"sale", authority.key().as_ref(), token_mint.key().as_ref()],
+        bump
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Treasury::INIT_SPACE,
+        seeds = [b"treasury", sale.key().as_ref()],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ValidateSaleParams<'info> {
+    /// CHECK: Must be unused for `initialize_sale` to create it; checked in the handler
+    #[account(
+        seeds = [b"sale", authority.key().as_ref(), token_mint.key().as_ref()],
+        bump
+    )]
+    pub sale: UncheckedAccount<'info>,
+
+    /// CHECK: The prospective sale authority; a dry run needs no signature
+    pub authority: UncheckedAccount<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    /// CHECK: Must be unused for `initialize_sale` to create it; checked in the handler
+    #[account(
+        seeds = [b"treasury", sale.key().as_ref()],
+        bump
+    )]
+    pub treasury: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PurchaseTokens<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + UserPurchase::INIT_SPACE, // discriminator + user + sale + tokens_purchased + sol_contributed + bump
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub user_purchase: Account<'info, UserPurchase>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = token_mint,
+        associated_token::authority = buyer,
+    )]
+    pub buyer_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Treasury account (validated in sale state)
+    #[account(mut, address = sale.treasury)]
+    pub treasury: AccountInfo<'info>,
+
+    /// Only required when `auto_stake` is set
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + StakePosition::INIT_SPACE,
+        seeds = [b"stake", sale.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub stake_position: Option<Account<'info, StakePosition>>,
+
+    /// Only required when `auto_stake` is set
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = token_mint,
+        associated_token::authority = stake_position,
+    )]
+    pub stake_token_account: Option<Account<'info, TokenAccount>>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        seeds = [b"agg_config", token_mint.key().as_ref()],
+        bump = aggregate_config.bump
+    )]
+    pub aggregate_config: Option<Account<'info, AggregateConfig>>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + AggregateLimit::INIT_SPACE,
+        seeds = [b"agg", token_mint.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub aggregate_limit: Option<Account<'info, AggregateLimit>>,
+
+    /// CHECK: Only used as the referral seed; must match the `referrer` argument
+    pub referrer: Option<UncheckedAccount<'info>>,
+
+    /// Only required when purchasing with a referrer
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + ReferralAccount::INIT_SPACE,
+        seeds = [
+            b"referral",
+            sale.key().as_ref(),
+            referrer.as_ref().ok_or(ErrorCode::MissingReferralAccounts)?.key().as_ref()
+        ],
+        bump
+    )]
+    pub referral: Option<Account<'info, ReferralAccount>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> PurchaseTokens<'info> {
+    /// SOL purchase paid for by the buyer, for `purchase_tokens`, `purchase_with_sol`
+    /// and `purchase_reserved`
+    fn process_purchase(
+        &mut self,
+        bumps: &PurchaseTokensBumps,
+        token_amount: u64,
+        auto_stake: bool,
+        proof: &[[u8; 32]],
+        referrer: Option<Pubkey>,
+        valid_until: i64,
+        snapshot: Option<&SnapshotProof>,
+    ) -> Result<PurchaseReceipt> {
+        SolPurchase {
+            sale: &mut self.sale,
+            user_purchase: &mut self.user_purchase,
+            buyer: self.buyer.to_account_info(),
+            payer: self.buyer.to_account_info(),
+            sale_token_vault: &self.sale_token_vault,
+            buyer_token_account: &self.buyer_token_account,
+            treasury: &self.treasury,
+            stake_position: &mut self.stake_position,
+            stake_token_account: &self.stake_token_account,
+            aggregate_config: &self.aggregate_config,
+            aggregate_limit: &mut self.aggregate_limit,
+            referrer: &self.referrer,
+            referral: &mut self.referral,
+            token_program: &self.token_program,
+            system_program: &self.system_program,
+            bumps: PurchaseBumps {
+                user_purchase: bumps.user_purchase,
+                stake_position: bumps.stake_position,
+                aggregate_limit: bumps.aggregate_limit,
+                referral: bumps.referral,
+            },
+        }
+        .process(
+            token_amount,
+            auto_stake,
+            proof,
+            referrer,
+            valid_until,
+            snapshot,
+        )
+    }
+}
+
+/// Bumps of the accounts a SOL purchase may create
+struct PurchaseBumps {
+    user_purchase: u8,
+    stake_position: Option<u8>,
+    aggregate_limit: Option<u8>,
+    referral: Option<u8>,
+}
+
+/// The accounts of a SOL purchase, shared by `PurchaseTokens` and `PurchaseSponsored`.
+/// `payer` pays the SOL cost; everything else keys off `buyer`.
+struct SolPurchase<'a, 'info> {
+    sale: &'a mut Account<'info, Sale>,
+    user_purchase: &'a mut Account<'info, UserPurchase>,
+    buyer: AccountInfo<'info>,
+    payer: AccountInfo<'info>,
+    sale_token_vault: &'a Account<'info, TokenAccount>,
+    buyer_token_account: &'a Account<'info, TokenAccount>,
+    treasury: &'a AccountInfo<'info>,
+    stake_position: &'a mut Option<Account<'info, StakePosition>>,
+    stake_token_account: &'a Option<Account<'info, TokenAccount>>,
+    aggregate_config: &'a Option<Account<'info, AggregateConfig>>,
+    aggregate_limit: &'a mut Option<Account<'info, AggregateLimit>>,
+    referrer: &'a Option<UncheckedAccount<'info>>,
+    referral: &'a mut Option<Account<'info, ReferralAccount>>,
+    token_program: &'a Program<'info, Token>,
+    system_program: &'a Program<'info, System>,
+    bumps: PurchaseBumps,
+}
+
+impl SolPurchase<'_, '_> {
+    fn process(
+        self,
+        token_amount: u64,
+        auto_stake: bool,
+        proof: &[[u8; 32]],
+        referrer: Option<Pubkey>,
+        valid_until: i64,
+        snapshot: Option<&SnapshotProof>,
+    ) -> Result<PurchaseReceipt> {
+        let sale = &mut *self.sale;
+        let clock = Clock::get()?;
+
+        require!(
+            !math::purchase_expired(valid_until, clock.unix_timestamp),
+            ErrorCode::PurchaseExpired
+        );
+        sale.assert_open(clock.unix_timestamp)?;
+        sale.assert_whitelisted(&self.buyer.key(), proof, clock.unix_timestamp)?;
+
+        // Staked purchases receive a bonus that also comes out of the sale supply
+        let bonus_amount = if auto_stake {
+            require!(sale.stake_lock_duration > 0, ErrorCode::StakingDisabled);
+            require!(
+                sale.delivers_instantly(),
+                ErrorCode::StakingRequiresInstantDelivery
+            );
+            (token_amount as u128)
+                .checked_mul(sale.stake_bonus_bps as u128)
+                .ok_or(ErrorCode::MathOverflow)?
+                .checked_div(BPS_DENOMINATOR as u128)
+                .ok_or(ErrorCode::MathOverflow)? as u64
+        } else {
+            0
+        };
+        let delivered_amount = token_amount
+            .checked_add(bonus_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // The referrer's bonus is held in the vault until `claim_referral_bonus`
+        let referral_bonus = match referrer {
+            Some(referrer) => {
+                require!(referrer != self.buyer.key(), ErrorCode::SelfReferral);
+                require!(
+                    self.referrer.as_ref().map(|account| account.key()) == Some(referrer),
+                    ErrorCode::MissingReferralAccounts
+                );
+                (token_amount as u128)
+                    .checked_mul(sale.referral_bps as u128)
+                    .ok_or(ErrorCode::MathOverflow)?
+                    .checked_div(BPS_DENOMINATOR as u128)
+                    .ok_or(ErrorCode::MathOverflow)? as u64
+            }
+            None => {
+                // Otherwise the payer would fund a referral account that is never written
+                require!(self.referral.is_none(), ErrorCode::MissingReferralAccounts);
+                0
+            }
+        };
+        let supply_amount = delivered_amount
+            .checked_add(referral_bonus)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let user_purchase = &mut *self.user_purchase;
+        sale.assert_cooldown_elapsed(user_purchase.last_purchase_ts, clock.unix_timestamp)?;
+        sale.assert_purchase_limits(
+            token_amount,
+            supply_amount,
+            user_purchase.tokens_purchased,
+            clock.unix_timestamp,
+        )?;
+        if sale.enforce_aggregate_limit {
+            let config = self
+                .aggregate_config
+                .as_ref()
+                .ok_or(ErrorCode::MissingAggregateAccounts)?;
+            self.aggregate_limit
+                .as_mut()
+                .ok_or(ErrorCode::MissingAggregateAccounts)?
+                .record(
+                    self.buyer.key(),
+                    sale,
+                    token_amount,
+                    config.max_aggregate,
+                    user_purchase.tokens_purchased,
+                    clock.unix_timestamp,
+                    self.bumps
+                        .aggregate_limit
+                        .ok_or(ErrorCode::MissingAggregateAccounts)?,
+                )?;
+            user_purchase.aggregate_counted = user_purchase
+                .aggregate_counted
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        // Calculate SOL cost, less any snapshot holder discount
+        let discount_bps = sale.snapshot_discount_bps(&sale.key(), &self.buyer.key(), snapshot)?;
+        let sol_cost = sale.apply_discount(sale.calculate_sol_cost(token_amount)?, discount_bps)?;
+        // Rounding a dust-sized cost down must not hand out free tokens
+        require!(sol_cost > 0, ErrorCode::ZeroCost);
+        let average_price = sale.average_price(sol_cost, token_amount)?;
+        sale.assert_lamport_floor(sol_cost, delivered_amount)?;
+
+        // Transfer SOL from the payer to treasury
+        let transfer_instruction = SystemTransfer {
+            from: self.payer.to_account_info(),
+            to: self.treasury.to_account_info(),
+        };
+
+        transfer(
+            CpiContext::new(self.system_program.to_account_info(), transfer_instruction),
+            sol_cost,
+        )?;
+
+        if sale.delivers_instantly() {
+            // Transfer tokens from sale vault to the buyer, or into their stake lockup
+            let destination = if auto_stake {
+                self.stake_token_account
+                    .as_ref()
+                    .ok_or(ErrorCode::MissingStakeAccounts)?
+                    .to_account_info()
+            } else {
+                self.buyer_token_account.to_account_info()
+            };
+
+            let seeds = &[
+                b"sale",
+                sale.seed_authority.as_ref(),
+                sale.token_mint.as_ref(),
+                &[sale.bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let cpi_accounts = Transfer {
+                from: self.sale_token_vault.to_account_info(),
+                to: destination,
+                authority: sale.to_account_info(),
+            };
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    self.token_program.to_account_info(),
+                    cpi_accounts,
+                    signer,
+                ),
+                delivered_amount,
+            )?;
+
+            if auto_stake {
+                let stake = self
+                    .stake_position
+                    .as_mut()
+                    .ok_or(ErrorCode::MissingStakeAccounts)?;
+
+                // Every staked purchase restarts the lockup for the whole position
+                let unlock_time = clock
+                    .unix_timestamp
+                    .checked_add(sale.stake_lock_duration)
+                    .ok_or(ErrorCode::MathOverflow)?;
+
+                stake.owner = self.buyer.key();
+                stake.sale = sale.key();
+                stake.amount = stake
+                    .amount
+                    .checked_add(delivered_amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                stake.bonus_amount = stake
+                    .bonus_amount
+                    .checked_add(bonus_amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                stake.unlock_time = stake.unlock_time.max(unlock_time);
+                stake.multiplier_bps = sale.stake_multiplier_bps;
+                stake.bump = self
+                    .bumps
+                    .stake_position
+                    .ok_or(ErrorCode::MissingStakeAccounts)?;
+
+                sale.stake_bonus_allocated = sale
+                    .stake_bonus_allocated
+                    .checked_add(bonus_amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+
+                emit!(TokensStaked {
+                    owner: stake.owner,
+                    sale: stake.sale,
+                    amount: delivered_amount,
+                    bonus_amount,
+                    unlock_time: stake.unlock_time,
+                    multiplier_bps: stake.multiplier_bps,
+                });
+
+                user_purchase.tokens_staked = user_purchase
+                    .tokens_staked
+                    .checked_add(token_amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+            }
+
+            user_purchase.tokens_claimed = user_purchase
+                .tokens_claimed
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            // Vesting sales keep tokens in the vault until `claim_tokens`
+            sale.tokens_owed = sale
+                .tokens_owed
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        // Update state
+        sale.tokens_sold += token_amount;
+        sale.total_raised += sol_cost;
+        user_purchase.user = self.buyer.key();
+        user_purchase.sale = sale.key();
+        user_purchase.bump = self.bumps.user_purchase;
+        user_purchase.record_purchase_time(clock.unix_timestamp)?;
+        user_purchase.tokens_purchased += token_amount;
+        user_purchase.sol_contributed += sol_cost;
+
+        emit!(TokensPurchased {
+            buyer: self.buyer.key(),
+            token_amount,
+            sol_cost,
+            average_price,
+            total_tokens_sold: sale.tokens_sold,
+            total_raised: sale.total_raised,
+            valid_until,
+            discount_bps,
+        });
+
+        if let Some(referrer) = referrer {
+            let referral = self
+                .referral
+                .as_mut()
+                .ok_or(ErrorCode::MissingReferralAccounts)?;
+            referral.referrer = referrer;
+            referral.sale = sale.key();
+            referral.bonus_tokens_earned = referral
+                .bonus_tokens_earned
+                .checked_add(referral_bonus)
+                .ok_or(ErrorCode::MathOverflow)?;
+            referral.referred_volume = referral
+                .referred_volume
+                .checked_add(token_amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+            referral.bump = self
+                .bumps
+                .referral
+                .ok_or(ErrorCode::MissingReferralAccounts)?;
+
+            sale.referral_bonus_allocated = sale
+                .referral_bonus_allocated
+                .checked_add(referral_bonus)
+                .ok_or(ErrorCode::MathOverflow)?;
+
+            emit!(ReferralRecorded {
+                sale: sale.key(),
+                buyer: self.buyer.key(),
+                referrer,
+                token_amount,
+                bonus_amount: referral_bonus,
+                total_bonus_earned: referral.bonus_tokens_earned,
+                referred_volume: referral.referred_volume,
+            });
+        }
+
+        Ok(PurchaseReceipt {
+            tokens_delivered: if sale.delivers_instantly() {
+                delivered_amount
+            } else {
+                0
+            },
+            sol_cost,
+            price_used: average_price,
+            user_total: user_purchase.tokens_purchased,
+        })
+    }
+}
+
+#[derive(Accounts)]
+pub struct PurchaseTokensWithSpl<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + UserPurchase::INIT_SPACE,
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub user_purchase: Account<'info, UserPurchase>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = token_mint,
+        associated_token::authority = buyer,
+    )]
+    pub buyer_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = sale.payment_mint @ ErrorCode::InvalidPaymentMint)]
+    pub payment_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = buyer_payment_account.mint == payment_mint.key() @ ErrorCode::InvalidPaymentMint,
+        constraint = buyer_payment_account.owner == buyer.key() @ ErrorCode::Unauthorized
+    )]
+    pub buyer_payment_account: Account<'info, TokenAccount>,
+
+    #[account(mut, address = sale.payment_treasury_ata @ ErrorCode::InvalidPaymentTreasury)]
+    pub payment_treasury: Account<'info, TokenAccount>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        seeds = [b"agg_config", token_mint.key().as_ref()],
+        bump = aggregate_config.bump
+    )]
+    pub aggregate_config: Option<Account<'info, AggregateConfig>>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + AggregateLimit::INIT_SPACE,
+        seeds = [b"agg", token_mint.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub aggregate_limit: Option<Account<'info, AggregateLimit>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct PurchaseSponsored<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        space = 8 + UserPurchase::INIT_SPACE,
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub user_purchase: Account<'info, UserPurchase>,
+
+    /// Owns the allocation
+    pub buyer: Signer<'info>,
+
+    /// Pays the SOL cost and any rent
+    #[account(mut)]
+    pub sponsor: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        associated_token::mint = token_mint,
+        associated_token::authority = buyer,
+    )]
+    pub buyer_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Treasury account (validated in sale state)
+    #[account(mut, address = sale.treasury)]
+    pub treasury: AccountInfo<'info>,
+
+    /// Only required when `auto_stake` is set
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        space = 8 + StakePosition::INIT_SPACE,
+        seeds = [b"stake", sale.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub stake_position: Option<Account<'info, StakePosition>>,
+
+    /// Only required when `auto_stake` is set
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        associated_token::mint = token_mint,
+        associated_token::authority = stake_position,
+    )]
+    pub stake_token_account: Option<Account<'info, TokenAccount>>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        seeds = [b"agg_config", token_mint.key().as_ref()],
+        bump = aggregate_config.bump
+    )]
+    pub aggregate_config: Option<Account<'info, AggregateConfig>>,
+
+    /// Only required when the sale enforces the mint's aggregate limit
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        space = 8 + AggregateLimit::INIT_SPACE,
+        seeds = [b"agg", token_mint.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub aggregate_limit: Option<Account<'info, AggregateLimit>>,
+
+    /// CHECK: Only used as the referral seed; must match the `referrer` argument
+    pub referrer: Option<UncheckedAccount<'info>>,
+
+    /// Only required when purchasing with a referrer
+    #[account(
+        init_if_needed,
+        payer = sponsor,
+        space = 8 + ReferralAccount::INIT_SPACE,
+        seeds = [
+            b"referral",
+            sale.key().as_ref(),
+            referrer.as_ref().ok_or(ErrorCode::MissingReferralAccounts)?.key().as_ref()
+        ],
+        bump
+    )]
+    pub referral: Option<Account<'info, ReferralAccount>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(address = stake_position.sale)]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"stake", stake_position.sale.as_ref(), owner.key().as_ref()],
+        bump = stake_position.bump,
+        has_one = owner @ ErrorCode::Unauthorized
+    )]
+    pub stake_position: Account<'info, StakePosition>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = stake_position,
+    )]
+    pub stake_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        associated_token::mint = token_mint,
+        associated_token::authority = owner,
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TogglePause<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct HaltAndReport<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint,
+        has_one = treasury
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct EndSale<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeSale<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump
+    )]
+    pub sale: Account<'info, Sale>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRefund<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump = user_purchase.bump
+    )]
+    pub user_purchase: Account<'info, UserPurchase>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = buyer,
+    )]
+    pub buyer_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"treasury", sale.key().as_ref()],
+        bump = sale.treasury_bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+
+    /// Required if the buyer auto-staked in this sale
+    #[account(
+        mut,
+        seeds = [b"stake", sale.key().as_ref(), buyer.key().as_ref()],
+        bump = stake_position.bump
+    )]
+    pub stake_position: Option<Account<'info, StakePosition>>,
+
+    /// Required if the buyer auto-staked in this sale
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = stake_position,
+    )]
+    pub stake_token_account: Option<Account<'info, TokenAccount>>,
+
+    /// Only required if the purchase counted toward the mint's aggregate limit
+    #[account(
+        mut,
+        seeds = [b"agg", token_mint.key().as_ref(), buyer.key().as_ref()],
+        bump = aggregate_limit.bump
+    )]
+    pub aggregate_limit: Option<Account<'info, AggregateLimit>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ProcessRefundsBatch<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        seeds = [b"treasury", sale.key().as_ref()],
+        bump = sale.treasury_bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTokens<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump = user_purchase.bump
+    )]
+    pub user_purchase: Account<'info, UserPurchase>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = token_mint,
+        associated_token::authority = buyer,
+    )]
+    pub buyer_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTokens<'info> {
+    #[account(
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = sale,
+    )]
+    pub sale_token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint,
+        associated_token::authority = authority,
+    )]
+    pub authority_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateSaleParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaymentMint<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+
+    pub payment_mint: Account<'info, Mint>,
+
+    /// Receives SPL payments directly
+    #[account(
+        constraint = payment_treasury.mint == payment_mint.key() @ ErrorCode::InvalidPaymentMint
+    )]
+    pub payment_treasury: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowlist<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetReservationTtl<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetTiers<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetSnapshotRoot<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetLamportFloor<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTreasury<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        has_one = treasury
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub treasury: Account<'info, Treasury>,
+
+    /// CHECK: Any account can receive the withdrawn SOL
+    #[account(mut)]
+    pub destination: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
+    )]
+    pub sale: Account<'info, Sale>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
     #[account(
-        init,
-        payer = authority,
-        space = Sale::INIT_SPACE,
-        seeds = [b"sale", authority.key().as_ref(), token_mint.key().as_ref()],
-        bump
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump
     )]
     pub sale: Account<'info, Sale>,
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    pub new_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAggregateLimit<'info> {
+    #[account(
+        init_if_needed,
+        payer = mint_authority,
+        space = 8 + AggregateConfig::INIT_SPACE,
+        seeds = [b"agg_config", token_mint.key().as_ref()],
+        bump
+    )]
+    pub aggregate_config: Account<'info, AggregateConfig>,
 
+    #[account(
+        constraint = token_mint.mint_authority == COption::Some(mint_authority.key())
+            @ ErrorCode::Unauthorized
+    )]
     pub token_mint: Account<'info, Mint>,
 
-    /// CHECK: Treasury account to receive SOL payments
-    pub treasury: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint_authority: Signer<'info>,
 
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct PurchaseTokens<'info> {
+pub struct SetEnforceAggregateLimit<'info> {
     #[account(
         mut,
-        seeds = [b"sale", sale.authority.as_ref(), token_mint.key().as_ref()],
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
         bump = sale.bump,
-        has_one = token_mint @ ErrorCode::InvalidTokenMint
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = !sale.immutable @ ErrorCode::SaleIsImmutable
     )]
     pub sale: Account<'info, Sale>,
 
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReserveAllocation<'info> {
     #[account(
-        init_if_needed,
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), sale.token_mint.as_ref()],
+        bump = sale.bump
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        init,
         payer = buyer,
-        space = 8 + UserPurchase::INIT_SPACE, // discriminator + user + sale + tokens_purchased + sol_contributed + bump
-        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        space = 8 + Reservation::INIT_SPACE,
+        seeds = [b"reservation", sale.key().as_ref(), buyer.key().as_ref()],
         bump
     )]
-    pub user_purchase: Account<'info, UserPurchase>,
+    pub reservation: Account<'info, Reservation>,
+
+    /// Omitted for wallets that have not purchased yet
+    #[account(
+        seeds = [b"purchase", sale.key().as_ref(), buyer.key().as_ref()],
+        bump = user_purchase.bump
+    )]
+    pub user_purchase: Option<Account<'info, UserPurchase>>,
 
     #[account(mut)]
     pub buyer: Signer<'info>,
 
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PurchaseReserved<'info> {
+    pub purchase: PurchaseTokens<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"reservation", purchase.sale.key().as_ref(), purchase.buyer.key().as_ref()],
+        bump = reservation.bump
+    )]
+    pub reservation: Account<'info, Reservation>,
+}
+
+#[derive(Accounts)]
+pub struct ReleaseReservation<'info> {
+    #[account(mut)]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        seeds = [b"reservation", sale.key().as_ref(), reservation.buyer.as_ref()],
+        bump = reservation.bump,
+        close = buyer
+    )]
+    pub reservation: Account<'info, Reservation>,
+
+    /// CHECK: Receives the reservation rent back
+    #[account(mut, address = reservation.buyer)]
+    pub buyer: UncheckedAccount<'info>,
+
+    pub caller: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralBonus<'info> {
+    #[account(
+        mut,
+        seeds = [b"sale", sale.seed_authority.as_ref(), token_mint.key().as_ref()],
+        bump = sale.bump,
+        has_one = token_mint @ ErrorCode::InvalidTokenMint
+    )]
+    pub sale: Account<'info, Sale>,
+
+    #[account(
+        mut,
+        seeds = [b"referral", sale.key().as_ref(), referrer.key().as_ref()],
+        bump = referral.bump
+    )]
+    pub referral: Account<'info, ReferralAccount>,
+
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
     pub token_mint: Account<'info, Mint>,
 
     #[account(
@@ -337,122 +2828,878 @@ pub struct PurchaseTokens<'info> {
     )]
     pub sale_token_vault: Account<'info, TokenAccount>,
 
-    #[account(
-        init_if_needed,
-        payer = buyer,
-        associated_token::mint = token_mint,
-        associated_token::authority = buyer,
-    )]
-    pub buyer_token_account: Account<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = referrer,
+        associated_token::mint = token_mint,
+        associated_token::authority = referrer,
+    )]
+    pub referrer_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Reconcile<'info> {
+    #[account(has_one = treasury)]
+    pub sale: Account<'info, Sale>,
+
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct GetUserInfo<'info> {
+    pub sale: Account<'info, Sale>,
+
+    /// CHECK: Only used to derive the purchase PDA
+    pub user: UncheckedAccount<'info>,
+
+    /// Omitted for wallets that have not purchased yet
+    #[account(
+        seeds = [b"purchase", sale.key().as_ref(), user.key().as_ref()],
+        bump = user_purchase.bump
+    )]
+    pub user_purchase: Option<Account<'info, UserPurchase>>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Sale {
+    pub authority: Pubkey,
+    /// Authority the sale PDA was derived from; stays fixed across authority handovers
+    pub seed_authority: Pubkey,
+    /// Proposed by `propose_authority`, takes over once it calls `accept_authority`
+    pub pending_authority: Option<Pubkey>,
+    pub token_mint: Pubkey,
+    pub treasury: Pubkey,
+    /// Lamports per whole token (10^token_decimals base units)
+    pub token_price: u64,
+    pub token_decimals: u8,
+    /// Token amounts below are all in base units
+    pub max_tokens: u64,
+    pub min_purchase: u64,
+    pub max_purchase: u64,
+    pub tokens_sold: u64,
+    pub total_raised: u64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub is_active: bool,
+    pub is_paused: bool,
+    pub stake_bonus_bps: u16,
+    pub stake_lock_duration: i64,
+    pub stake_multiplier_bps: u16,
+    pub stake_bonus_allocated: u64,
+    pub payment_mint: Pubkey,
+    pub payment_treasury_ata: Pubkey,
+    pub token_price_usd: u64,
+    pub total_raised_usd: u64,
+    pub soft_cap: u64,
+    pub outcome: SaleOutcome,
+    pub treasury_bump: u8,
+    pub tge_bps: u16,
+    pub cliff_seconds: i64,
+    pub vesting_duration_seconds: i64,
+    /// Purchased tokens still held in the vault for buyers to claim
+    pub tokens_owed: u64,
+    /// Merkle root of `keccak(wallet)` leaves allowed to buy before `whitelist_end_time`
+    pub whitelist_root: [u8; 32],
+    pub whitelist_end_time: i64,
+    /// Per-wallet cap that replaces `max_purchase` during the whitelist window
+    pub whitelist_max_purchase: u64,
+    /// Supply held by unexpired or unreleased reservations
+    pub tokens_reserved: u64,
+    pub reservation_ttl_secs: i64,
+    /// Minimum lamports per whole token delivered by SOL purchases (0 disables)
+    pub min_lamports_per_token: u64,
+    /// Whether purchases count against the mint's `AggregateConfig` cap
+    pub enforce_aggregate_limit: bool,
+    /// Number of active entries in `tiers`; 0 prices every token at `token_price`
+    pub tier_count: u8,
+    pub tiers: [PriceTier; MAX_PRICE_TIERS],
+    /// Referrer bonus in basis points of each referred purchase
+    pub referral_bps: u16,
+    /// Referral bonuses earned so far; they count against `max_tokens`
+    pub referral_bonus_allocated: u64,
+    pub referral_bonus_claimed: u64,
+    /// Set at initialization and never cleared; blocks every parameter change
+    pub immutable: bool,
+    /// Cap on a single purchase, unlike the lifetime `max_purchase` (0 disables)
+    pub max_purchase_per_tx: u64,
+    pub purchase_cooldown_seconds: i64,
+    /// Seconds after `start_time` during which `max_purchase_per_tx` is halved
+    pub launch_guard_seconds: i64,
+    /// Lamports returned to buyers by `claim_refund`
+    pub refunds_paid: u64,
+    /// Lamports moved out by `withdraw_treasury`
+    pub treasury_withdrawn: u64,
+    /// Merkle root of `keccak(sale, wallet, balance)` leaves from the holder snapshot
+    pub snapshot_root: [u8; 32],
+    pub discount_tier_count: u8,
+    pub discount_tiers: [DiscountTier; MAX_DISCOUNT_TIERS],
+    /// Purchases must be whole multiples of this many base units (1 disables)
+    pub lot_size: u64,
+    /// UTF-8 label of the currency `token_price_usd` is quoted in, zero-padded
+    pub quote_symbol: [u8; MAX_QUOTE_SYMBOL_LEN],
+    /// Display decimals of `token_price_usd`; costs always use `USD_PRICE_DECIMALS`
+    pub quote_decimals: u8,
+    pub rounding: RoundingPolicy,
+    pub bump: u8,
+}
+
+/// Lamports per whole token for sales up to `token_cap_cumulative` tokens sold
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq, InitSpace, Debug,
+)]
+pub struct PriceTier {
+    pub price: u64,
+    pub token_cap_cumulative: u64,
+}
+
+/// Discount for wallets that held at least `min_balance` at the snapshot
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq, InitSpace, Debug,
+)]
+pub struct DiscountTier {
+    pub min_balance: u64,
+    pub discount_bps: u16,
+}
+
+/// Arguments of `initialize_sale`, as checked by `validate_sale_params`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InitSaleParams {
+    pub token_price: u64,
+    pub max_tokens: u64,
+    pub min_purchase: u64,
+    pub max_purchase: u64,
+    pub sale_duration: i64,
+    pub start_delay: i64,
+    pub stake_bonus_bps: u16,
+    pub stake_lock_duration: i64,
+    pub stake_multiplier_bps: u16,
+    pub soft_cap: u64,
+    pub tge_bps: u16,
+    pub cliff_seconds: i64,
+    pub vesting_duration_seconds: i64,
+    pub referral_bps: u16,
+    pub immutable: bool,
+    pub max_purchase_per_tx: u64,
+    pub purchase_cooldown_seconds: i64,
+    pub launch_guard_seconds: i64,
+    pub lot_size: u64,
+    pub quote_symbol: Option<String>,
+    pub quote_decimals: Option<u8>,
+    pub rounding: Option<RoundingPolicy>,
+}
+
+impl InitSaleParams {
+    // Failed-check bits, in the order `initialize_sale` reports them
+    pub const PRICE: u32 = 1 << 0;
+    pub const MAX_TOKENS: u32 = 1 << 1;
+    pub const PURCHASE_LIMITS: u32 = 1 << 2;
+    pub const DURATION: u32 = 1 << 3;
+    pub const STAKE_CONFIG: u32 = 1 << 4;
+    pub const SOFT_CAP: u32 = 1 << 5;
+    pub const VESTING_SCHEDULE: u32 = 1 << 6;
+    pub const REFERRAL: u32 = 1 << 7;
+    pub const ANTI_BOT_DURATIONS: u32 = 1 << 8;
+    pub const PER_TX_CAP: u32 = 1 << 9;
+    pub const LOT_SIZE: u32 = 1 << 10;
+    pub const SCHEDULE_OVERFLOW: u32 = 1 << 11;
+    pub const QUOTE_CURRENCY: u32 = 1 << 12;
+    // Account checks, reported only by `validate_sale_params`
+    pub const SALE_EXISTS: u32 = 1 << 13;
+    pub const TREASURY_EXISTS: u32 = 1 << 14;
+
+    /// Bitmask of every parameter check that fails when initializing at `now`
+    pub fn failed_checks(&self, now: i64) -> u32 {
+        let checks = [
+            (self.token_price > 0, Self::PRICE),
+            (self.max_tokens > 0, Self::MAX_TOKENS),
+            (
+                self.min_purchase > 0 && self.min_purchase <= self.max_purchase,
+                Self::PURCHASE_LIMITS,
+            ),
+            (
+                self.sale_duration > 0 && self.start_delay >= 0,
+                Self::DURATION,
+            ),
+            (
+                self.stake_bonus_bps as u64 <= BPS_DENOMINATOR && self.stake_lock_duration >= 0,
+                Self::STAKE_CONFIG,
+            ),
+            (self.soft_cap <= self.max_tokens, Self::SOFT_CAP),
+            (
+                self.tge_bps as u64 <= BPS_DENOMINATOR
+                    && self.cliff_seconds >= 0
+                    && self.vesting_duration_seconds >= 0
+                    && (self.tge_bps as u64 == BPS_DENOMINATOR
+                        || self.vesting_duration_seconds > 0),
+                Self::VESTING_SCHEDULE,
+            ),
+            (self.referral_bps <= MAX_REFERRAL_BPS, Self::REFERRAL),
+            (
+                self.purchase_cooldown_seconds >= 0 && self.launch_guard_seconds >= 0,
+                Self::ANTI_BOT_DURATIONS,
+            ),
+            (
+                self.max_purchase_per_tx == 0 || self.max_purchase_per_tx >= self.min_purchase,
+                Self::PER_TX_CAP,
+            ),
+            (
+                self.lot_size > 0
+                    && self.min_purchase % self.lot_size == 0
+                    && self.max_purchase % self.lot_size == 0
+                    && self.max_purchase_per_tx % self.lot_size == 0,
+                Self::LOT_SIZE,
+            ),
+            (self.schedule(now).is_some(), Self::SCHEDULE_OVERFLOW),
+            (self.quote_currency().is_some(), Self::QUOTE_CURRENCY),
+        ];
+
+        checks
+            .iter()
+            .filter(|(passed, _)| !passed)
+            .fold(0, |failed, (_, check)| failed | check)
+    }
+
+    /// Fail with the error of the first failed check, if any
+    pub fn validate(&self, now: i64) -> Result<()> {
+        let failed = self.failed_checks(now);
+        if failed == 0 {
+            return Ok(());
+        }
+        let error = match 1 << failed.trailing_zeros() {
+            Self::PRICE => ErrorCode::InvalidPrice,
+            Self::MAX_TOKENS => ErrorCode::InvalidAmount,
+            Self::PURCHASE_LIMITS | Self::PER_TX_CAP => ErrorCode::InvalidPurchaseLimit,
+            Self::DURATION | Self::ANTI_BOT_DURATIONS => ErrorCode::InvalidDuration,
+            Self::STAKE_CONFIG => ErrorCode::InvalidStakeConfig,
+            Self::SOFT_CAP => ErrorCode::InvalidSoftCap,
+            Self::VESTING_SCHEDULE => ErrorCode::InvalidVestingSchedule,
+            Self::REFERRAL => ErrorCode::InvalidReferralConfig,
+            Self::LOT_SIZE => ErrorCode::InvalidLotSize,
+            Self::SCHEDULE_OVERFLOW => ErrorCode::MathOverflow,
+            _ => ErrorCode::InvalidQuoteCurrency,
+        };
+        Err(error.into())
+    }
+
+    /// `(start_time, end_time)` when initialized at `now`, or None on overflow
+    pub fn schedule(&self, now: i64) -> Option<(i64, i64)> {
+        let start_time = now.checked_add(self.start_delay)?;
+        Some((start_time, start_time.checked_add(self.sale_duration)?))
+    }
+
+    /// Zero-padded quote symbol and decimals, defaulting to USD with 8 decimals,
+    /// or None if either is out of bounds
+    pub fn quote_currency(&self) -> Option<([u8; MAX_QUOTE_SYMBOL_LEN], u8)> {
+        let symbol = self.quote_symbol.as_deref().unwrap_or(DEFAULT_QUOTE_SYMBOL);
+        let decimals = self.quote_decimals.unwrap_or(USD_PRICE_DECIMALS as u8);
+        if symbol.is_empty()
+            || symbol.len() > MAX_QUOTE_SYMBOL_LEN
+            || symbol.contains('\0')
+            || decimals > MAX_QUOTE_DECIMALS
+        {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_QUOTE_SYMBOL_LEN];
+        bytes[..symbol.len()].copy_from_slice(symbol.as_bytes());
+        Some((bytes, decimals))
+    }
+}
+
+/// A buyer's snapshot balance and the Merkle proof of its leaf
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SnapshotProof {
+    pub balance: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// How a sale rounds fractional costs; token amounts always round down (see `math`)
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug, Default,
+)]
+pub enum RoundingPolicy {
+    /// Round costs up, so the treasury never receives less than the exact cost
+    #[default]
+    CeilCost,
+    /// Round costs down, so buyers never pay more than the exact cost
+    FloorCost,
+    /// Round costs to the nearest lamport, ties to even
+    HalfEven,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum SaleOutcome {
+    Pending,
+    Success,
+    Failed,
+}
+
+/// Program-owned account holding SOL proceeds until the sale resolves
+#[account]
+#[derive(InitSpace)]
+pub struct Treasury {
+    pub sale: Pubkey,
+    pub bump: u8,
+}
+
+/// Short-lived hold on part of the sale supply, converted by `purchase_reserved`
+#[account]
+#[derive(InitSpace)]
+pub struct Reservation {
+    pub buyer: Pubkey,
+    pub sale: Pubkey,
+    pub token_amount: u64,
+    pub expires_at: i64,
+    pub bump: u8,
+}
+
+/// Per-wallet cap shared by every sale of a mint (`[b"agg_config", mint]`)
+#[account]
+#[derive(InitSpace)]
+pub struct AggregateConfig {
+    pub mint: Pubkey,
+    pub max_aggregate: u64,
+    pub bump: u8,
+}
+
+/// A wallet's purchases across all enforcing sales of a mint (`[b"agg", mint, wallet]`)
+#[account]
+#[derive(InitSpace)]
+pub struct AggregateLimit {
+    pub wallet: Pubkey,
+    pub mint: Pubkey,
+    pub total_purchased: u64,
+    pub bump: u8,
+}
+
+impl AggregateLimit {
+    /// Add a purchase in `sale` to the wallet's running total, failing past `max_aggregate`.
+    /// `already_purchased` is the wallet's total in `sale`, for the remaining-allowance log.
+    pub fn record(
+        &mut self,
+        wallet: Pubkey,
+        sale: &Sale,
+        token_amount: u64,
+        max_aggregate: u64,
+        already_purchased: u64,
+        now: i64,
+        bump: u8,
+    ) -> Result<()> {
+        let total = self
+            .total_purchased
+            .checked_add(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let within_aggregate = total <= max_aggregate;
+        if !within_aggregate {
+            let aggregate_remaining = max_aggregate.saturating_sub(self.total_purchased);
+            sale.log_remaining_allowance(already_purchased, Some(aggregate_remaining), now)?;
+        }
+        require!(within_aggregate, ErrorCode::ExceedsAggregateLimit);
+
+        self.wallet = wallet;
+        self.mint = sale.token_mint;
+        self.total_purchased = total;
+        self.bump = bump;
+        Ok(())
+    }
+
+    /// Take refunded tokens back out of the wallet's running total
+    pub fn release(&mut self, token_amount: u64) {
+        self.total_purchased = self.total_purchased.saturating_sub(token_amount);
+    }
+}
+
+/// Bonus a referrer has earned in one sale (`[b"referral", sale, referrer]`)
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralAccount {
+    pub referrer: Pubkey,
+    pub sale: Pubkey,
+    pub bonus_tokens_earned: u64,
+    pub bonus_tokens_claimed: u64,
+    /// Tokens bought by wallets this referrer referred
+    pub referred_volume: u64,
+    pub bump: u8,
+}
+
+impl Sale {
+    /// Tokens that have left (or are owed from) the supply, including staking and
+    /// referral bonuses and reservation holds
+    pub fn tokens_committed(&self) -> Result<u64> {
+        Ok(self
+            .tokens_sold
+            .checked_add(self.stake_bonus_allocated)
+            .and_then(|v| v.checked_add(self.referral_bonus_allocated))
+            .and_then(|v| v.checked_add(self.tokens_reserved))
+            .ok_or(ErrorCode::MathOverflow)?)
+    }
+
+    /// Whether purchases deliver tokens immediately instead of vesting
+    pub fn delivers_instantly(&self) -> bool {
+        self.tge_bps as u64 == BPS_DENOMINATOR
+    }
+
+    /// Portion of `total` vested at `now`: the TGE share once claims open at
+    /// `end_time + cliff_seconds`, then the rest linearly over the vesting duration
+    pub fn vested_amount(&self, total: u64, now: i64) -> Result<u64> {
+        let claim_start = self
+            .end_time
+            .checked_add(self.cliff_seconds)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(now >= claim_start, ErrorCode::ClaimBeforeCliff);
+
+        if self.delivers_instantly() {
+            return Ok(total);
+        }
+
+        let tge = (total as u128)
+            .checked_mul(self.tge_bps as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / BPS_DENOMINATOR as u128;
+        let elapsed = (now - claim_start).min(self.vesting_duration_seconds) as u128;
+        let linear = (total as u128 - tge)
+            .checked_mul(elapsed)
+            .ok_or(ErrorCode::MathOverflow)?
+            / self.vesting_duration_seconds as u128;
+
+        Ok((tge + linear) as u64)
+    }
+
+    /// Whether the sale has been ended early or run past `end_time`
+    pub fn has_ended(&self, now: i64) -> bool {
+        !self.is_active || now > self.end_time
+    }
+
+    /// Check that the sale is accepting purchases at `now`
+    pub fn assert_open(&self, now: i64) -> Result<()> {
+        require!(self.is_active, ErrorCode::SaleInactive);
+        require!(!self.is_paused, ErrorCode::SalePaused);
+        require!(
+            now >= self.start_time && now <= self.end_time,
+            ErrorCode::SaleNotActive
+        );
+        Ok(())
+    }
+
+    /// Whether only allowlisted wallets may buy at `now`
+    pub fn in_whitelist_window(&self, now: i64) -> bool {
+        self.whitelist_root != [0; 32] && now < self.whitelist_end_time
+    }
+
+    /// Per-wallet purchase cap in effect at `now`
+    pub fn max_purchase_at(&self, now: i64) -> u64 {
+        if self.in_whitelist_window(now) {
+            self.whitelist_max_purchase
+        } else {
+            self.max_purchase
+        }
+    }
+
+    /// During the whitelist window, require a Merkle proof that `buyer` is allowlisted
+    pub fn assert_whitelisted(&self, buyer: &Pubkey, proof: &[[u8; 32]], now: i64) -> Result<()> {
+        if self.in_whitelist_window(now) {
+            require!(
+                merkle::verify(proof, &self.whitelist_root, merkle::leaf(buyer)),
+                ErrorCode::NotWhitelisted
+            );
+        }
+        Ok(())
+    }
+
+    /// Discount unlocked by a snapshot proof: the highest tier the proven balance
+    /// reaches. No proof means no discount; a proof that doesn't verify fails.
+    pub fn snapshot_discount_bps(
+        &self,
+        sale_key: &Pubkey,
+        buyer: &Pubkey,
+        snapshot: Option<&SnapshotProof>,
+    ) -> Result<u16> {
+        let Some(snapshot) = snapshot else {
+            return Ok(0);
+        };
+
+        require!(
+            self.snapshot_root != [0; 32]
+                && merkle::verify(
+                    &snapshot.proof,
+                    &self.snapshot_root,
+                    merkle::snapshot_leaf(sale_key, buyer, snapshot.balance),
+                ),
+            ErrorCode::InvalidSnapshotProof
+        );
+
+        Ok(self.discount_tiers[..self.discount_tier_count as usize]
+            .iter()
+            .rev()
+            .find(|tier| snapshot.balance >= tier.min_balance)
+            .map_or(0, |tier| tier.discount_bps))
+    }
+
+    /// `sol_cost` less `discount_bps`, rounded under the sale's `rounding` policy
+    pub fn apply_discount(&self, sol_cost: u64, discount_bps: u16) -> Result<u64> {
+        let discounted = (sol_cost as u128)
+            .checked_mul(BPS_DENOMINATOR as u128 - discount_bps as u128)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let discounted = self.rounding.div(discounted, BPS_DENOMINATOR as u128);
+        u64::try_from(discounted).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
+
+    /// Per-transaction cap at `now`: halved during the launch guard window, but never
+    /// below `min_purchase`. 0 means uncapped.
+    pub fn max_purchase_per_tx_at(&self, now: i64) -> u64 {
+        let in_launch_guard = now < self.start_time.saturating_add(self.launch_guard_seconds);
+        if self.max_purchase_per_tx > 0 && in_launch_guard {
+            (self.max_purchase_per_tx / 2).max(self.min_purchase)
+        } else {
+            self.max_purchase_per_tx
+        }
+    }
+
+    /// Reject a wallet's purchase until `purchase_cooldown_seconds` after its last one.
+    /// Fresh purchase records (`last_purchase_ts == 0`) are never blocked.
+    pub fn assert_cooldown_elapsed(&self, last_purchase_ts: i64, now: i64) -> Result<()> {
+        if self.purchase_cooldown_seconds == 0 || last_purchase_ts == 0 {
+            return Ok(());
+        }
+
+        let ready_at = last_purchase_ts.saturating_add(self.purchase_cooldown_seconds);
+        if now < ready_at {
+            msg!("cooldown_remaining_seconds={}", ready_at - now);
+        }
+        require!(now >= ready_at, ErrorCode::CooldownActive);
+        Ok(())
+    }
+
+    /// Check a purchase against the per-purchase, supply, and per-wallet limits.
+    /// `supply_amount` is what leaves the sale supply, including any bonus.
+    pub fn assert_purchase_limits(
+        &self,
+        token_amount: u64,
+        supply_amount: u64,
+        already_purchased: u64,
+        now: i64,
+    ) -> Result<()> {
+        require!(
+            token_amount >= self.min_purchase,
+            ErrorCode::BelowMinimumPurchase
+        );
+        require!(token_amount % self.lot_size == 0, ErrorCode::InvalidLotSize);
+
+        let max_purchase = self.max_purchase_at(now);
+        let within_purchase_max = token_amount <= max_purchase;
+        if !within_purchase_max {
+            self.log_remaining_allowance(already_purchased, None, now)?;
+        }
+        require!(within_purchase_max, ErrorCode::ExceedsMaximumPurchase);
+
+        let max_per_tx = self.max_purchase_per_tx_at(now);
+        let within_tx_cap = max_per_tx == 0 || token_amount <= max_per_tx;
+        if !within_tx_cap {
+            msg!("max_purchase_per_tx={}", max_per_tx);
+            self.log_remaining_allowance(already_purchased, None, now)?;
+        }
+        require!(within_tx_cap, ErrorCode::ExceedsPerTransactionLimit);
+
+        let within_supply = self
+            .tokens_committed()?
+            .checked_add(supply_amount)
+            .ok_or(ErrorCode::MathOverflow)?
+            <= self.max_tokens;
+        if !within_supply {
+            self.log_remaining_allowance(already_purchased, None, now)?;
+        }
+        require!(within_supply, ErrorCode::ExceedsMaxTokens);
+
+        let within_wallet_limit = already_purchased
+            .checked_add(token_amount)
+            .ok_or(ErrorCode::MathOverflow)?
+            <= max_purchase;
+        if !within_wallet_limit {
+            self.log_remaining_allowance(already_purchased, None, now)?;
+        }
+        require!(within_wallet_limit, ErrorCode::ExceedsUserLimit);
+
+        Ok(())
+    }
 
-    /// CHECK: Treasury account (validated in sale state)
-    #[account(mut, address = sale.treasury)]
-    pub treasury: AccountInfo<'info>,
+    /// Tokens a wallet that has bought `already_purchased` can still buy at `now`,
+    /// as (allowance, wallet_remaining, supply_remaining). The allowance is in whole lots.
+    pub fn remaining_allowance(&self, already_purchased: u64, now: i64) -> Result<(u64, u64, u64)> {
+        let wallet_remaining = self.max_purchase_at(now).saturating_sub(already_purchased);
+        let supply_remaining = self.max_tokens.saturating_sub(self.tokens_committed()?);
+        Ok((
+            self.round_down_to_lot(wallet_remaining.min(supply_remaining)),
+            wallet_remaining,
+            supply_remaining,
+        ))
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    /// Logged right before a limit error so clients can see what is still purchasable.
+    /// `aggregate_remaining` is the wallet's headroom under the cross-sale aggregate cap.
+    fn log_remaining_allowance(
+        &self,
+        already_purchased: u64,
+        aggregate_remaining: Option<u64>,
+        now: i64,
+    ) -> Result<()> {
+        let (allowance, wallet_remaining, supply_remaining) =
+            self.remaining_allowance(already_purchased, now)?;
+        match aggregate_remaining {
+            Some(aggregate_remaining) => msg!(
+                "remaining_allowance={} wallet_remaining={} supply_remaining={} aggregate_remaining={}",
+                self.round_down_to_lot(allowance.min(aggregate_remaining)),
+                wallet_remaining,
+                supply_remaining,
+                aggregate_remaining
+            ),
+            None => msg!(
+                "remaining_allowance={} wallet_remaining={} supply_remaining={}",
+                allowance,
+                wallet_remaining,
+                supply_remaining
+            ),
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct TogglePause<'info> {
-    #[account(
-        mut,
-        seeds = [b"sale", authority.key().as_ref(), sale.token_mint.as_ref()],
-        bump = sale.bump,
-        has_one = authority @ ErrorCode::Unauthorized
-    )]
-    pub sale: Account<'info, Sale>,
+    /// Active tiers, or the flat `token_price` as a single tier covering the supply
+    fn price_tiers(&self) -> Vec<PriceTier> {
+        if self.tier_count == 0 {
+            vec![PriceTier {
+                price: self.token_price,
+                token_cap_cumulative: self.max_tokens,
+            }]
+        } else {
+            self.tiers[..self.tier_count as usize].to_vec()
+        }
+    }
 
-    pub authority: Signer<'info>,
-}
+    /// Lamport cost of `token_amount` base units, rounded under the sale's `rounding` policy.
+    /// Purchases that cross a tier boundary pay each tier's price for its share.
+    pub fn calculate_sol_cost(&self, token_amount: u64) -> Result<u64> {
+        let mut position = self.tokens_sold;
+        let mut remaining = token_amount;
+        let mut numerator: u128 = 0;
+
+        for tier in self.price_tiers() {
+            if remaining == 0 {
+                break;
+            }
+            if position >= tier.token_cap_cumulative {
+                continue;
+            }
+            let in_tier = remaining.min(tier.token_cap_cumulative - position);
+            numerator = numerator
+                .checked_add(in_tier as u128 * tier.price as u128)
+                .ok_or(ErrorCode::MathOverflow)?;
+            position += in_tier;
+            remaining -= in_tier;
+        }
+        // Flat pricing keeps charging `token_price` past `max_tokens`, as before tiers
+        if remaining > 0 {
+            require!(self.tier_count == 0, ErrorCode::ExceedsMaxTokens);
+            numerator = numerator
+                .checked_add(remaining as u128 * self.token_price as u128)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
 
-#[derive(Accounts)]
-pub struct EndSale<'info> {
-    #[account(
-        mut,
-        seeds = [b"sale", authority.key().as_ref(), sale.token_mint.as_ref()],
-        bump = sale.bump,
-        has_one = authority @ ErrorCode::Unauthorized
-    )]
-    pub sale: Account<'info, Sale>,
+        let cost = self.rounding.div(numerator, self.token_unit()?);
+        u64::try_from(cost).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
 
-    pub authority: Signer<'info>,
-}
+    /// Base units in one whole token. Errors instead of overflowing on mints with
+    /// more than 38 decimals.
+    pub fn token_unit(&self) -> Result<u128> {
+        Ok(10u128
+            .checked_pow(self.token_decimals as u32)
+            .ok_or(ErrorCode::MathOverflow)?)
+    }
 
-#[derive(Accounts)]
-pub struct WithdrawTokens<'info> {
-    #[account(
-        seeds = [b"sale", authority.key().as_ref(), token_mint.key().as_ref()],
-        bump = sale.bump,
-        has_one = authority @ ErrorCode::Unauthorized,
-        has_one = token_mint @ ErrorCode::InvalidTokenMint
-    )]
-    pub sale: Account<'info, Sale>,
+    /// Average lamports per whole token paid for `token_amount` base units
+    pub fn average_price(&self, sol_cost: u64, token_amount: u64) -> Result<u64> {
+        let price = (sol_cost as u128)
+            .checked_mul(self.token_unit()?)
+            .ok_or(ErrorCode::MathOverflow)?
+            / token_amount as u128;
+        u64::try_from(price).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    /// Reject SOL purchases where the treasury receives less than `min_lamports_per_token`
+    /// per whole token delivered. Bonus tokens count as delivered, so they lower the rate.
+    pub fn assert_lamport_floor(&self, sol_cost: u64, delivered_amount: u64) -> Result<()> {
+        if self.min_lamports_per_token == 0 {
+            return Ok(());
+        }
 
-    pub token_mint: Account<'info, Mint>,
+        let lamports_per_token = (sol_cost as u128)
+            .checked_mul(self.token_unit()?)
+            .ok_or(ErrorCode::MathOverflow)?
+            / delivered_amount as u128;
+        let above_floor = lamports_per_token >= self.min_lamports_per_token as u128;
+        if !above_floor {
+            msg!(
+                "lamports_per_token={} below floor={}; pay with purchase_tokens_with_spl instead",
+                lamports_per_token,
+                self.min_lamports_per_token
+            );
+        }
+        require!(above_floor, ErrorCode::BelowLamportFloor);
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = sale,
-    )]
-    pub sale_token_vault: Account<'info, TokenAccount>,
+    /// Book a refund: `undelivered` tokens are no longer owed and `refund_amount`
+    /// lamports left the treasury
+    pub fn record_refund(&mut self, undelivered: u64, refund_amount: u64) -> Result<()> {
+        self.tokens_owed = self
+            .tokens_owed
+            .checked_sub(undelivered)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.refunds_paid = self
+            .refunds_paid
+            .checked_add(refund_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        associated_token::mint = token_mint,
-        associated_token::authority = authority,
-    )]
-    pub authority_token_account: Account<'info, TokenAccount>,
+    /// Largest whole number of lots in `token_amount`
+    pub fn round_down_to_lot(&self, token_amount: u64) -> u64 {
+        token_amount - token_amount % self.lot_size
+    }
 
-    pub token_program: Program<'info, Token>,
-}
+    /// Base units `sol_amount` lamports buys from the current tier onwards,
+    /// rounded down in the sale's favor
+    pub fn tokens_for_sol(&self, sol_amount: u64) -> Result<u64> {
+        // Work in lamports scaled by 10^decimals so tier prices divide exactly
+        let mut budget = (sol_amount as u128)
+            .checked_mul(self.token_unit()?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let mut position = self.tokens_sold;
+        let mut tokens: u128 = 0;
+
+        for tier in self.price_tiers() {
+            if position >= tier.token_cap_cumulative {
+                continue;
+            }
+            let in_tier = (tier.token_cap_cumulative - position) as u128;
+            let tier_cost = in_tier * tier.price as u128;
+            if budget < tier_cost {
+                tokens += budget / tier.price as u128;
+                budget = 0;
+                break;
+            }
+            tokens += in_tier;
+            budget -= tier_cost;
+            position = tier.token_cap_cumulative;
+        }
+        // Flat pricing has no supply limit here; the caller caps it to what is left
+        if budget > 0 && self.tier_count == 0 {
+            tokens += budget / self.token_price as u128;
+        }
 
-#[derive(Accounts)]
-pub struct UpdateSaleParams<'info> {
-    #[account(
-        mut,
-        seeds = [b"sale", authority.key().as_ref(), sale.token_mint.as_ref()],
-        bump = sale.bump,
-        has_one = authority @ ErrorCode::Unauthorized
-    )]
-    pub sale: Account<'info, Sale>,
+        u64::try_from(tokens).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
 
-    pub authority: Signer<'info>,
+    /// Cost in payment mint base units, rounded up so buyers never underpay by dust
+    pub fn calculate_payment_cost(&self, token_amount: u64, payment_decimals: u8) -> Result<u64> {
+        let payment_unit = 10u128
+            .checked_pow(payment_decimals as u32)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let numerator = (token_amount as u128)
+            .checked_mul(self.token_price_usd as u128)
+            .and_then(|v| v.checked_mul(payment_unit))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let denominator = 10u128
+            .checked_pow(USD_PRICE_DECIMALS + self.token_decimals as u32)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let cost = numerator.div_ceil(denominator);
+        u64::try_from(cost).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
 }
 
 #[account]
 #[derive(InitSpace)]
-pub struct Sale {
-    pub authority: Pubkey,
-    pub token_mint: Pubkey,
-    pub treasury: Pubkey,
-    pub token_price: u64,
-    pub max_tokens: u64,
-    pub min_purchase: u64,
-    pub max_purchase: u64,
-    pub tokens_sold: u64,
-    pub total_raised: u64,
-    pub start_time: i64,
-    pub end_time: i64,
-    pub is_active: bool,
-    pub is_paused: bool,
+pub struct UserPurchase {
+    pub user: Pubkey,
+    pub sale: Pubkey,
+    pub tokens_purchased: u64,
+    pub sol_contributed: u64,
+    pub usd_contributed: u64,
+    /// Tokens delivered so far, at purchase or through `claim_tokens` (bonuses excluded)
+    pub tokens_claimed: u64,
+    pub refunded: bool,
     pub bump: u8,
+    pub last_purchase_ts: i64,
+    pub purchase_count: u32,
+    /// Tokens counted against the wallet's aggregate limit, released on refund
+    pub aggregate_counted: u64,
+    /// Purchased tokens delivered into the wallet's stake lockup (bonuses excluded)
+    pub tokens_staked: u64,
+}
+
+impl UserPurchase {
+    /// Stamp a purchase for the wallet's cooldown
+    pub fn record_purchase_time(&mut self, now: i64) -> Result<()> {
+        self.last_purchase_ts = now;
+        self.purchase_count = self
+            .purchase_count
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
 }
 
 #[account]
 #[derive(InitSpace)]
-pub struct UserPurchase {
-    pub user: Pubkey,
+pub struct StakePosition {
+    pub owner: Pubkey,
     pub sale: Pubkey,
+    pub amount: u64,
+    pub bonus_amount: u64,
+    pub unlock_time: i64,
+    pub multiplier_bps: u16,
+    pub bump: u8,
+}
+
+/// Return data of SOL purchases, read by CPI callers with `get_return_data`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PurchaseReceipt {
+    /// Tokens sent to the buyer or their stake, bonus included (0 on vesting sales)
+    pub tokens_delivered: u64,
+    pub sol_cost: u64,
+    /// Average lamports per whole token
+    pub price_used: u64,
+    /// Buyer's `tokens_purchased` after this purchase
+    pub user_total: u64,
+}
+
+/// Return data of `get_user_info`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct UserInfo {
     pub tokens_purchased: u64,
     pub sol_contributed: u64,
-    pub bump: u8,
+    pub usd_contributed: u64,
+    pub remaining_allowance: u64,
+    pub sale_immutable: bool,
+    pub quote_symbol: [u8; MAX_QUOTE_SYMBOL_LEN],
+    pub quote_decimals: u8,
+}
+
+/// Return data of `reconcile`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Reconciliation {
+    pub total_raised: u64,
+    pub refunds_paid: u64,
+    pub treasury_withdrawn: u64,
+    /// Treasury lamports above its rent-exempt minimum
+    pub treasury_balance: u64,
+    /// `treasury_balance + refunds_paid + treasury_withdrawn - total_raised`; 0 when
+    /// the books balance, positive if lamports were sent to the treasury directly
+    pub drift: i64,
 }
 
 #[event]
@@ -461,9 +3708,16 @@ pub struct SaleInitialized {
     pub authority: Pubkey,
     pub token_mint: Pubkey,
     pub token_price: u64,
+    pub token_decimals: u8,
     pub max_tokens: u64,
+    pub soft_cap: u64,
     pub start_time: i64,
     pub end_time: i64,
+    pub immutable: bool,
+    pub lot_size: u64,
+    pub quote_symbol: [u8; MAX_QUOTE_SYMBOL_LEN],
+    pub quote_decimals: u8,
+    pub rounding: RoundingPolicy,
 }
 
 #[event]
@@ -471,8 +3725,47 @@ pub struct TokensPurchased {
     pub buyer: Pubkey,
     pub token_amount: u64,
     pub sol_cost: u64,
+    /// Lamports per whole token, averaged across any tiers the purchase spans
+    pub average_price: u64,
     pub total_tokens_sold: u64,
     pub total_raised: u64,
+    /// Buyer-supplied deadline the purchase was checked against (0 if none)
+    pub valid_until: i64,
+    /// Snapshot holder discount applied to `sol_cost`
+    pub discount_bps: u16,
+}
+
+#[event]
+pub struct TokensPurchasedWithSpl {
+    pub buyer: Pubkey,
+    pub payment_mint: Pubkey,
+    pub token_amount: u64,
+    pub payment_amount: u64,
+    pub total_tokens_sold: u64,
+    pub total_raised_usd: u64,
+}
+
+#[event]
+pub struct TokensPurchasedSponsored {
+    /// Paid for the purchase in the `TokensPurchased` emitted just before
+    pub sponsor: Pubkey,
+}
+
+#[event]
+pub struct TokensStaked {
+    pub owner: Pubkey,
+    pub sale: Pubkey,
+    pub amount: u64,
+    pub bonus_amount: u64,
+    pub unlock_time: i64,
+    pub multiplier_bps: u16,
+}
+
+#[event]
+pub struct TokensUnstaked {
+    pub owner: Pubkey,
+    pub sale: Pubkey,
+    pub amount: u64,
 }
 
 #[event]
@@ -481,6 +3774,24 @@ pub struct SaleToggled {
     pub is_paused: bool,
 }
 
+/// Post-pause state reported by `halt_and_report`
+#[event]
+pub struct SaleHalted {
+    pub sale: Pubkey,
+    pub was_paused: bool,
+    pub is_paused: bool,
+    pub is_active: bool,
+    pub tokens_sold: u64,
+    pub tokens_committed: u64,
+    pub tokens_owed: u64,
+    pub total_raised: u64,
+    pub total_raised_usd: u64,
+    /// Treasury lamports above its rent-exempt minimum
+    pub treasury_balance: u64,
+    pub vault_balance: u64,
+    pub timestamp: i64,
+}
+
 #[event]
 pub struct SaleEnded {
     pub sale: Pubkey,
@@ -489,6 +3800,44 @@ pub struct SaleEnded {
     pub end_time: i64,
 }
 
+#[event]
+pub struct SaleFinalized {
+    pub sale: Pubkey,
+    pub outcome: SaleOutcome,
+    pub tokens_sold: u64,
+    pub soft_cap: u64,
+    pub total_raised: u64,
+}
+
+#[event]
+pub struct RefundClaimed {
+    pub sale: Pubkey,
+    pub buyer: Pubkey,
+    pub tokens_returned: u64,
+    pub sol_refunded: u64,
+}
+
+/// Summary of one `process_refunds_batch` call
+#[event]
+pub struct RefundBatchProcessed {
+    pub sale: Pubkey,
+    pub refunded: u32,
+    pub already_refunded: u32,
+    /// Records with delivered tokens, which need the buyer's `claim_refund`
+    pub needs_buyer: u32,
+    /// Pairs not reached before compute ran low
+    pub unprocessed: u32,
+    pub lamports_refunded: u64,
+}
+
+#[event]
+pub struct TokensClaimed {
+    pub sale: Pubkey,
+    pub buyer: Pubkey,
+    pub amount: u64,
+    pub total_claimed: u64,
+}
+
 #[event]
 pub struct TokensWithdrawn {
     pub authority: Pubkey,
@@ -502,6 +3851,117 @@ pub struct SaleParamsUpdated {
     pub max_tokens: u64,
     pub min_purchase: u64,
     pub max_purchase: u64,
+    pub max_purchase_per_tx: u64,
+    pub purchase_cooldown_seconds: i64,
+    pub launch_guard_seconds: i64,
+}
+
+#[event]
+pub struct PaymentMintSet {
+    pub sale: Pubkey,
+    pub payment_mint: Pubkey,
+    pub payment_treasury_ata: Pubkey,
+    pub token_price_usd: u64,
+}
+
+#[event]
+pub struct AllowlistSet {
+    pub sale: Pubkey,
+    pub root: [u8; 32],
+    pub whitelist_end_time: i64,
+    pub whitelist_max_purchase: u64,
+}
+
+#[event]
+pub struct ReservationTtlSet {
+    pub sale: Pubkey,
+    pub reservation_ttl_secs: i64,
+}
+
+#[event]
+pub struct AllocationReserved {
+    pub sale: Pubkey,
+    pub buyer: Pubkey,
+    pub token_amount: u64,
+    pub expires_at: i64,
+}
+
+#[event]
+pub struct ReservationReleased {
+    pub sale: Pubkey,
+    pub buyer: Pubkey,
+    pub token_amount: u64,
+    pub released_by: Pubkey,
+}
+
+#[event]
+pub struct SnapshotRootSet {
+    pub sale: Pubkey,
+    pub root: [u8; 32],
+    pub tiers: Vec<DiscountTier>,
+}
+
+#[event]
+pub struct LamportFloorSet {
+    pub sale: Pubkey,
+    pub min_lamports_per_token: u64,
+}
+
+#[event]
+pub struct AggregateLimitSet {
+    pub mint: Pubkey,
+    pub max_aggregate: u64,
+}
+
+#[event]
+pub struct AggregateEnforcementSet {
+    pub sale: Pubkey,
+    pub enforce: bool,
+}
+
+#[event]
+pub struct PriceTiersSet {
+    pub sale: Pubkey,
+    pub tiers: Vec<PriceTier>,
+}
+
+#[event]
+pub struct TreasuryWithdrawn {
+    pub sale: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct AuthorityProposed {
+    pub sale: Pubkey,
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+}
+
+#[event]
+pub struct AuthorityTransferred {
+    pub sale: Pubkey,
+    pub previous_authority: Pubkey,
+    pub new_authority: Pubkey,
+}
+
+#[event]
+pub struct ReferralRecorded {
+    pub sale: Pubkey,
+    pub buyer: Pubkey,
+    pub referrer: Pubkey,
+    pub token_amount: u64,
+    pub bonus_amount: u64,
+    pub total_bonus_earned: u64,
+    pub referred_volume: u64,
+}
+
+#[event]
+pub struct ReferralBonusClaimed {
+    pub sale: Pubkey,
+    pub referrer: Pubkey,
+    pub amount: u64,
 }
 
 #[error_code]
@@ -538,4 +3998,94 @@ pub enum ErrorCode {
     SaleAlreadyStarted,
     #[msg("Invalid token mint")]
     InvalidTokenMint,
+    #[msg("Invalid staking configuration")]
+    InvalidStakeConfig,
+    #[msg("Staking is not enabled for this sale")]
+    StakingDisabled,
+    #[msg("Stake accounts are required for auto-staked purchases")]
+    MissingStakeAccounts,
+    #[msg("Staked tokens are still locked")]
+    StakeStillLocked,
+    #[msg("SPL payments are not enabled for this sale")]
+    PaymentMintNotSet,
+    #[msg("Invalid payment mint")]
+    InvalidPaymentMint,
+    #[msg("Invalid payment treasury account")]
+    InvalidPaymentTreasury,
+    #[msg("Payment mint cannot change after SPL purchases")]
+    PaymentMintLocked,
+    #[msg("Soft cap cannot exceed max tokens")]
+    InvalidSoftCap,
+    #[msg("Sale has already been finalized")]
+    SaleAlreadyFinalized,
+    #[msg("Refunds are only available for failed sales")]
+    RefundNotAvailable,
+    #[msg("Purchase has already been refunded")]
+    AlreadyRefunded,
+    #[msg("Sales with a soft cap only accept refundable SOL payments")]
+    SoftCapRequiresSolPayments,
+    #[msg("Invalid vesting schedule")]
+    InvalidVestingSchedule,
+    #[msg("Auto-staking requires instant token delivery")]
+    StakingRequiresInstantDelivery,
+    #[msg("Sale must be finalized as successful first")]
+    SaleNotSuccessful,
+    #[msg("Tokens cannot be claimed before the cliff")]
+    ClaimBeforeCliff,
+    #[msg("No newly vested tokens to claim")]
+    NothingToClaim,
+    #[msg("Wallet is not on the sale allowlist")]
+    NotWhitelisted,
+    #[msg("Reservations are not enabled for this sale")]
+    ReservationsDisabled,
+    #[msg("Reservation has expired")]
+    ReservationExpired,
+    #[msg("Reservation has not expired")]
+    ReservationNotExpired,
+    #[msg("Slippage exceeded: fewer tokens than min_tokens_out")]
+    SlippageExceeded,
+    #[msg("SOL received per token is below the sale's lamport floor")]
+    BelowLamportFloor,
+    #[msg("Aggregate limit accounts are required for this sale")]
+    MissingAggregateAccounts,
+    #[msg("Exceeds the wallet's aggregate limit across sales")]
+    ExceedsAggregateLimit,
+    #[msg("Price tier caps must strictly increase and end at max tokens")]
+    InvalidPriceTiers,
+    #[msg("Tiered sales only accept SOL payments")]
+    TiersRequireSolPayments,
+    #[msg("Withdrawal exceeds the treasury balance above rent exemption")]
+    InsufficientTreasuryBalance,
+    #[msg("Signer is not the pending authority")]
+    NotPendingAuthority,
+    #[msg("Referral bonus cannot exceed MAX_REFERRAL_BPS")]
+    InvalidReferralConfig,
+    #[msg("Buyers cannot refer themselves")]
+    SelfReferral,
+    #[msg("Referral accounts are required and must match the referrer")]
+    MissingReferralAccounts,
+    #[msg("Sale vault does not hold enough unowed tokens")]
+    InsufficientVaultBalance,
+    #[msg("Sale parameters are immutable")]
+    SaleIsImmutable,
+    #[msg("Purchase cooldown is still active")]
+    CooldownActive,
+    #[msg("Exceeds the per-transaction purchase limit")]
+    ExceedsPerTransactionLimit,
+    #[msg("Purchase deadline has passed")]
+    PurchaseExpired,
+    #[msg("Discount tiers must strictly increase in balance and stay below 100%")]
+    InvalidDiscountTiers,
+    #[msg("Snapshot proof does not match the sale's snapshot root")]
+    InvalidSnapshotProof,
+    #[msg("Amount must be a multiple of the sale's lot size")]
+    InvalidLotSize,
+    #[msg("Quote symbol must be 1-8 bytes of UTF-8 and quote decimals at most 18")]
+    InvalidQuoteCurrency,
+    #[msg("Refund batch accounts must be writable (user_purchase, buyer) groups of this sale")]
+    InvalidRefundBatch,
+    #[msg("Purchase amount is too small to cost anything")]
+    ZeroCost,
+    #[msg("A sponsored purchase must be paid by a wallet other than the buyer")]
+    SelfSponsorship,
 }
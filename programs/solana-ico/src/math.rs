@@ -0,0 +1,210 @@
+//! Pure purchase arithmetic, kept free of accounts so it can be unit-tested
+//!
+//! # Rounding
+//!
+//! Fractional costs are rounded under a sale's `RoundingPolicy`. The policy applies wherever the program turns token amounts into a SOL cost:
+//! - `Sale::calculate_sol_cost`, and so every SOL purchase, including the charge of
+//!   `purchase_with_sol` for the tokens it derives
+//! - `Sale::apply_discount`, the snapshot discount taken off a SOL cost
+//!
+//! SPL payment costs (`Sale::calculate_payment_cost`) always round up. Under any
+//! policy, a purchase whose rounded cost is zero fails with `ZeroCost`.
+//!
+//! The policy does not apply to token amounts. Tokens derived from a SOL budget
+//! (`Sale::tokens_for_sol`) and bonuses in basis points always round down, so a
+//! charge never exceeds the budget and the sale never promises more than it holds.
+//! Refunds return exactly the lamports a buyer paid, so they involve no rounding.
+//!
+//! # Deadlines
+//!
+//! `purchase_expired` decides whether a purchase's `valid_until` has passed.
+
+use crate::RoundingPolicy;
+
+/// Whether a purchase with deadline `valid_until` is too late at `now`. The deadline
+/// second itself is still accepted, and 0 disables the check.
+pub fn purchase_expired(valid_until: i64, now: i64) -> bool {
+    valid_until != 0 && now > valid_until
+}
+
+impl RoundingPolicy {
+    /// `numerator / denominator` rounded under this policy. `denominator` must be non-zero.
+    pub fn div(self, numerator: u128, denominator: u128) -> u128 {
+        let quotient = numerator / denominator;
+        let remainder = numerator % denominator;
+        if remainder == 0 {
+            return quotient;
+        }
+
+        let round_up = match self {
+            RoundingPolicy::CeilCost => true,
+            RoundingPolicy::FloorCost => false,
+            // Compare the remainder with what is left to the next multiple, which
+            // avoids doubling the remainder and overflowing
+            RoundingPolicy::HalfEven => {
+                let to_next = denominator - remainder;
+                remainder > to_next || (remainder == to_next && quotient % 2 == 1)
+            }
+        };
+        quotient + round_up as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: [RoundingPolicy; 3] = [
+        RoundingPolicy::CeilCost,
+        RoundingPolicy::FloorCost,
+        RoundingPolicy::HalfEven,
+    ];
+
+    // Even denominators have an exact half-way point
+    const EVEN_DENOMINATORS: [u128; 5] = [2, 4, 10, 10_000, 1_000_000_000];
+
+    #[test]
+    fn exact_quotients_are_unchanged() {
+        for policy in POLICIES {
+            for denominator in [1, 3, 7, 10_000, 1_000_000_000] {
+                for quotient in 0..50u128 {
+                    assert_eq!(policy.div(quotient * denominator, denominator), quotient);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn half_way_points() {
+        for denominator in EVEN_DENOMINATORS {
+            for quotient in 0..50u128 {
+                let half = quotient * denominator + denominator / 2;
+                assert_eq!(
+                    RoundingPolicy::CeilCost.div(half, denominator),
+                    quotient + 1
+                );
+                assert_eq!(RoundingPolicy::FloorCost.div(half, denominator), quotient);
+
+                let even = if quotient % 2 == 0 {
+                    quotient
+                } else {
+                    quotient + 1
+                };
+                assert_eq!(RoundingPolicy::HalfEven.div(half, denominator), even);
+            }
+        }
+    }
+
+    #[test]
+    fn either_side_of_half_way() {
+        for denominator in EVEN_DENOMINATORS.into_iter().filter(|&d| d > 2) {
+            for quotient in 0..50u128 {
+                let half = quotient * denominator + denominator / 2;
+
+                let below = half - 1;
+                assert_eq!(
+                    RoundingPolicy::CeilCost.div(below, denominator),
+                    quotient + 1
+                );
+                assert_eq!(RoundingPolicy::FloorCost.div(below, denominator), quotient);
+                assert_eq!(RoundingPolicy::HalfEven.div(below, denominator), quotient);
+
+                let above = half + 1;
+                assert_eq!(
+                    RoundingPolicy::CeilCost.div(above, denominator),
+                    quotient + 1
+                );
+                assert_eq!(RoundingPolicy::FloorCost.div(above, denominator), quotient);
+                assert_eq!(
+                    RoundingPolicy::HalfEven.div(above, denominator),
+                    quotient + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn odd_denominators_have_no_tie() {
+        for denominator in [3u128, 5, 7, 9_999] {
+            for numerator in 0..(denominator * 20) {
+                let quotient = numerator / denominator;
+                let remainder = numerator % denominator;
+                let nearest = if 2 * remainder > denominator {
+                    quotient + 1
+                } else {
+                    quotient
+                };
+                assert_eq!(
+                    RoundingPolicy::HalfEven.div(numerator, denominator),
+                    nearest
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ceil_matches_div_ceil_and_floor_matches_division() {
+        for denominator in 1..=64u128 {
+            for numerator in 0..512u128 {
+                assert_eq!(
+                    RoundingPolicy::CeilCost.div(numerator, denominator),
+                    numerator.div_ceil(denominator)
+                );
+                assert_eq!(
+                    RoundingPolicy::FloorCost.div(numerator, denominator),
+                    numerator / denominator
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn large_values_do_not_overflow() {
+        for policy in POLICIES {
+            assert_eq!(policy.div(u128::MAX, u128::MAX), 1);
+        }
+
+        // Just below one
+        let almost_one = u128::MAX - 1;
+        assert_eq!(RoundingPolicy::CeilCost.div(almost_one, u128::MAX), 1);
+        assert_eq!(RoundingPolicy::FloorCost.div(almost_one, u128::MAX), 0);
+        assert_eq!(RoundingPolicy::HalfEven.div(almost_one, u128::MAX), 1);
+
+        // 2^127 / (2^128 - 1) is just above one half, and doubling the remainder overflows
+        let just_above_half = 1u128 << 127;
+        assert_eq!(RoundingPolicy::CeilCost.div(just_above_half, u128::MAX), 1);
+        assert_eq!(RoundingPolicy::FloorCost.div(just_above_half, u128::MAX), 0);
+        assert_eq!(RoundingPolicy::HalfEven.div(just_above_half, u128::MAX), 1);
+
+        // The largest possible tie rounds to the even quotient
+        let tie_denominator = u128::MAX - 1;
+        assert_eq!(
+            RoundingPolicy::HalfEven.div(tie_denominator / 2, tie_denominator),
+            0
+        );
+        assert_eq!(
+            RoundingPolicy::CeilCost.div(tie_denominator / 2, tie_denominator),
+            1
+        );
+    }
+
+    #[test]
+    fn default_keeps_rounding_costs_up() {
+        assert_eq!(RoundingPolicy::default(), RoundingPolicy::CeilCost);
+    }
+
+    #[test]
+    fn deadline_second_is_inclusive() {
+        let valid_until = 1_700_000_000;
+        assert!(!purchase_expired(valid_until, valid_until - 1));
+        assert!(!purchase_expired(valid_until, valid_until));
+        assert!(purchase_expired(valid_until, valid_until + 1));
+    }
+
+    #[test]
+    fn zero_deadline_never_expires() {
+        for now in [i64::MIN, -1, 0, 1, 1_700_000_000, i64::MAX] {
+            assert!(!purchase_expired(0, now));
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Merkle proofs for sale allowlists and holder snapshots
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
+
+/// Leaf committed to the allowlist tree for `wallet`
+pub fn leaf(wallet: &Pubkey) -> [u8; 32] {
+    keccak::hash(wallet.as_ref()).to_bytes()
+}
+
+/// Leaf committed to a holder snapshot for `wallet` holding `balance`. Binding the
+/// sale key keeps a proof from being replayed against another sale's snapshot.
+pub fn snapshot_leaf(sale: &Pubkey, wallet: &Pubkey, balance: u64) -> [u8; 32] {
+    keccak::hashv(&[sale.as_ref(), wallet.as_ref(), &balance.to_le_bytes()]).to_bytes()
+}
+
+/// Check that `proof` hashes `leaf` up to `root`. Each pair is hashed in sorted
+/// order, so proofs are just the sibling hashes without left/right flags.
+pub fn verify(proof: &[[u8; 32]], root: &[u8; 32], leaf: [u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        if node <= *sibling {
+            keccak::hashv(&[&node, sibling]).to_bytes()
+        } else {
+            keccak::hashv(&[sibling, &node]).to_bytes()
+        }
+    });
+    computed == *root
+}
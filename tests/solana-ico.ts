@@ -1,6 +1,7 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program, BN, web3 } from "@coral-xyz/anchor";
 import { IcoTokenSale } from "../target/types/ico_token_sale";
+import { PurchaseIntegrator } from "../target/types/purchase_integrator";
 import {
   TOKEN_PROGRAM_ID,
   ASSOCIATED_TOKEN_PROGRAM_ID,
@@ -8,8 +9,10 @@ import {
   createAssociatedTokenAccount,
   mintTo,
   getAssociatedTokenAddress,
+  getAssociatedTokenAddressSync,
   getAccount,
 } from "@solana/spl-token";
+import { keccak_256 } from "@noble/hashes/sha3";
 import { assert, expect } from "chai";
 
 describe("ICO Token Sale", () => {
@@ -17,13 +20,14 @@ describe("ICO Token Sale", () => {
   anchor.setProvider(provider);
 
   const program = anchor.workspace.IcoTokenSale as Program<IcoTokenSale>;
+  const integrator = anchor.workspace.PurchaseIntegrator as Program<PurchaseIntegrator>;
   const connection = provider.connection;
 
   // Test accounts
   let authority: web3.Keypair;
   let buyer1: web3.Keypair;
   let buyer2: web3.Keypair;
-  let treasury: web3.Keypair;
+  let paymentWallet: web3.Keypair;
   let tokenMint: web3.PublicKey;
   let authorityTokenAccount: web3.PublicKey;
   let saleTokenVault: web3.PublicKey;
@@ -31,21 +35,226 @@ describe("ICO Token Sale", () => {
   // PDAs
   let salePda: web3.PublicKey;
   let saleBump: number;
+  let treasuryPda: web3.PublicKey;
 
-  // Sale parameters
-  const TOKEN_PRICE = new BN(1_000_000); // 0.001 SOL per token
-  const MAX_TOKENS = new BN(1_000_000); // 1M tokens
-  const MIN_PURCHASE = new BN(100); // 100 tokens minimum
-  const MAX_PURCHASE = new BN(10_000); // 10K tokens maximum per user
-  const SALE_DURATION = new BN(3600); // 1 hour
+  const findTreasuryPda = (sale: web3.PublicKey) =>
+    web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury"), sale.toBuffer()],
+      program.programId
+    )[0];
+
+  // Wait until the cluster clock has passed `unixTime`
+  const waitForClock = async (unixTime: number) => {
+    for (;;) {
+      const blockTime = await connection.getBlockTime(await connection.getSlot());
+      if (blockTime !== null && blockTime > unixTime) return;
+      await new Promise(resolve => setTimeout(resolve, 500));
+    }
+  };
+
+  // Sale parameters (token amounts are in base units)
   const TOKEN_DECIMALS = 9;
+  const ONE_TOKEN = new BN(10).pow(new BN(TOKEN_DECIMALS));
+  const tokens = (amount: number) => new BN(amount).mul(ONE_TOKEN);
+  const TOKEN_PRICE = new BN(1_000_000); // 0.001 SOL per whole token
+  const MAX_TOKENS = tokens(1_000_000); // 1M tokens
+  const MIN_PURCHASE = tokens(100); // 100 tokens minimum
+  const MAX_PURCHASE = tokens(10_000); // 10K tokens maximum per user
+  const SALE_DURATION = new BN(3600); // 1 hour
+  const NO_START_DELAY = new BN(0);
+  const STAKE_BONUS_BPS = 1_000; // 10% bonus for auto-staked purchases
+  const STAKE_LOCK_DURATION = new BN(86_400); // 1 day lockup
+  const STAKE_MULTIPLIER_BPS = 15_000; // 1.5x rewards multiplier
+  const NO_SOFT_CAP = new BN(0);
+  const INSTANT_DELIVERY_BPS = 10_000; // tge_bps that delivers tokens at purchase
+  const NO_CLIFF = new BN(0);
+  const NO_VESTING = new BN(0);
+  const NO_REFERRAL_BONUS = 0;
+  const MUTABLE_SALE = false;
+  const NO_TX_CAP = new BN(0);
+  const NO_COOLDOWN = new BN(0);
+  const NO_LAUNCH_GUARD = new BN(0);
+  const NO_LOT_SIZE = new BN(1); // any whole base unit
+  const DEFAULT_QUOTE_SYMBOL = null; // "USD"
+  const DEFAULT_QUOTE_DECIMALS = null; // 8
+  const DEFAULT_ROUNDING = null; // CeilCost
+  const NO_DEADLINE = new BN(0);
+
+  // Lamports charged for `amount` base units at the flat TOKEN_PRICE, rounded up
+  const costOf = (amount: BN, price: BN = TOKEN_PRICE) => {
+    const numerator = amount.mul(price);
+    const cost = numerator.div(ONE_TOKEN);
+    return numerator.mod(ONE_TOKEN).isZero() ? cost : cost.addn(1);
+  };
+
+  type SaleConfig = {
+    price?: BN;
+    supply?: BN;
+    minPurchase?: BN;
+    maxPurchase?: BN;
+    startDelay?: BN;
+    softCap?: BN;
+    stakeLockDuration?: BN;
+    // Vesting instead of instant delivery
+    tgeBps?: number;
+    vestingSeconds?: BN;
+    referralBps?: number;
+    immutable?: boolean;
+    maxPurchasePerTx?: BN;
+    cooldownSeconds?: BN;
+    launchGuardSeconds?: BN;
+    lotSize?: BN;
+    quoteSymbol?: string;
+    quoteDecimals?: number;
+    rounding?: object;
+    // Share an existing mint instead of creating one
+    mint?: web3.PublicKey;
+    mintAuthority?: web3.Keypair;
+  };
+
+  // Create and fund a standalone instant-delivery sale with its own authority (and mint by default)
+  const createFundedSale = async (config: SaleConfig = {}) => {
+    const saleAuthority = web3.Keypair.generate();
+    await connection.requestAirdrop(saleAuthority.publicKey, 5 * web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 1000));
+
+    const mintAuthority = config.mintAuthority ?? saleAuthority;
+    const mint =
+      config.mint ??
+      (await createMint(
+        connection,
+        mintAuthority,
+        mintAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      ));
+    const [standaloneSalePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("sale"), saleAuthority.publicKey.toBuffer(), mint.toBuffer()],
+      program.programId
+    );
+    const vault = await getAssociatedTokenAddress(mint, standaloneSalePda, true);
+    const supply = config.supply ?? tokens(1_000);
+
+    await program.methods
+      .initializeSale(
+        config.price ?? TOKEN_PRICE,
+        supply,
+        config.minPurchase ?? MIN_PURCHASE,
+        config.maxPurchase ?? supply,
+        SALE_DURATION,
+        config.startDelay ?? NO_START_DELAY,
+        STAKE_BONUS_BPS,
+        config.stakeLockDuration ?? STAKE_LOCK_DURATION,
+        STAKE_MULTIPLIER_BPS,
+        config.softCap ?? NO_SOFT_CAP,
+        config.tgeBps ?? INSTANT_DELIVERY_BPS,
+        NO_CLIFF,
+        config.vestingSeconds ?? NO_VESTING,
+        config.referralBps ?? NO_REFERRAL_BONUS,
+        config.immutable ?? MUTABLE_SALE,
+        config.maxPurchasePerTx ?? NO_TX_CAP,
+        config.cooldownSeconds ?? NO_COOLDOWN,
+        config.launchGuardSeconds ?? NO_LAUNCH_GUARD,
+        config.lotSize ?? NO_LOT_SIZE,
+        config.quoteSymbol ?? DEFAULT_QUOTE_SYMBOL,
+        config.quoteDecimals ?? DEFAULT_QUOTE_DECIMALS,
+        config.rounding ?? DEFAULT_ROUNDING
+      )
+      .accounts({
+        sale: standaloneSalePda,
+        authority: saleAuthority.publicKey,
+        tokenMint: mint,
+        treasury: findTreasuryPda(standaloneSalePda),
+        systemProgram: web3.SystemProgram.programId,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+      })
+      .signers([saleAuthority])
+      .rpc();
+
+    await mintTo(
+      connection,
+      mintAuthority,
+      mint,
+      vault,
+      mintAuthority.publicKey,
+      BigInt(supply.toString())
+    );
+
+    return { authority: saleAuthority, mint, salePda: standaloneSalePda, vault };
+  };
+
+  type StandaloneSale = Awaited<ReturnType<typeof createFundedSale>>;
+
+  // Accounts for a plain (non-staking) SOL purchase
+  const solPurchaseAccounts = (sale: StandaloneSale, buyer: web3.PublicKey) => ({
+    sale: sale.salePda,
+    userPurchase: web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("purchase"), sale.salePda.toBuffer(), buyer.toBuffer()],
+      program.programId
+    )[0],
+    buyer,
+    tokenMint: sale.mint,
+    saleTokenVault: sale.vault,
+    buyerTokenAccount: getAssociatedTokenAddressSync(sale.mint, buyer),
+    treasury: findTreasuryPda(sale.salePda),
+    stakePosition: null,
+    stakeTokenAccount: null,
+    aggregateConfig: null,
+    aggregateLimit: null,
+    referrer: null,
+    referral: null,
+    tokenProgram: TOKEN_PROGRAM_ID,
+    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+    systemProgram: web3.SystemProgram.programId,
+    rent: web3.SYSVAR_RENT_PUBKEY,
+  });
+
+  // Sorted-pair keccak tree matching the program's Merkle verifier
+  const hashPair = (a: Buffer, b: Buffer) =>
+    Buffer.from(keccak_256(Buffer.compare(a, b) <= 0 ? Buffer.concat([a, b]) : Buffer.concat([b, a])));
+
+  const merkleTree = (leaves: Buffer[]) => {
+    const layers = [leaves];
+    while (layers[layers.length - 1].length > 1) {
+      const layer = layers[layers.length - 1];
+      const next: Buffer[] = [];
+      for (let i = 0; i < layer.length; i += 2) {
+        // An unpaired node is carried up unchanged
+        next.push(i + 1 < layer.length ? hashPair(layer[i], layer[i + 1]) : layer[i]);
+      }
+      layers.push(next);
+    }
+
+    return {
+      root: Array.from(layers[layers.length - 1][0]),
+      proof: (leafIndex: number) => {
+        let index = leafIndex;
+        const proof: number[][] = [];
+        for (const layer of layers.slice(0, -1)) {
+          const sibling = index ^ 1;
+          if (sibling < layer.length) {
+            proof.push(Array.from(layer[sibling]));
+          }
+          index >>= 1;
+        }
+        return proof;
+      },
+    };
+  };
+
+  const fundedWallet = async () => {
+    const wallet = web3.Keypair.generate();
+    await connection.requestAirdrop(wallet.publicKey, 10 * web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    return wallet;
+  };
 
   before(async () => {
     // Generate keypairs
     authority = web3.Keypair.generate();
     buyer1 = web3.Keypair.generate();
     buyer2 = web3.Keypair.generate();
-    treasury = web3.Keypair.generate();
+    paymentWallet = web3.Keypair.generate();
 
     // Airdrop SOL to test accounts
     await Promise.all([
@@ -90,6 +299,8 @@ describe("ICO Token Sale", () => {
       program.programId
     );
 
+    treasuryPda = findTreasuryPda(salePda);
+
     // Get sale token vault address
     saleTokenVault = await getAssociatedTokenAddress(
       tokenMint,
@@ -106,13 +317,30 @@ describe("ICO Token Sale", () => {
           MAX_TOKENS,
           MIN_PURCHASE,
           MAX_PURCHASE,
-          SALE_DURATION
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
         )
         .accounts({
           sale: salePda,
           authority: authority.publicKey,
           tokenMint: tokenMint,
-          treasury: treasury.publicKey,
+          treasury: treasuryPda,
           systemProgram: web3.SystemProgram.programId,
           rent: web3.SYSVAR_RENT_PUBKEY,
         })
@@ -123,7 +351,7 @@ describe("ICO Token Sale", () => {
       const saleAccount = await program.account.sale.fetch(salePda);
       assert.equal(saleAccount.authority.toString(), authority.publicKey.toString());
       assert.equal(saleAccount.tokenMint.toString(), tokenMint.toString());
-      assert.equal(saleAccount.treasury.toString(), treasury.publicKey.toString());
+      assert.equal(saleAccount.treasury.toString(), treasuryPda.toString());
       assert.equal(saleAccount.tokenPrice.toString(), TOKEN_PRICE.toString());
       assert.equal(saleAccount.maxTokens.toString(), MAX_TOKENS.toString());
       assert.equal(saleAccount.minPurchase.toString(), MIN_PURCHASE.toString());
@@ -145,13 +373,30 @@ describe("ICO Token Sale", () => {
             MAX_TOKENS,
             MIN_PURCHASE,
             MAX_PURCHASE,
-            SALE_DURATION
+            SALE_DURATION,
+            NO_START_DELAY,
+            STAKE_BONUS_BPS,
+            STAKE_LOCK_DURATION,
+            STAKE_MULTIPLIER_BPS,
+            NO_SOFT_CAP,
+            INSTANT_DELIVERY_BPS,
+            NO_CLIFF,
+            NO_VESTING,
+            NO_REFERRAL_BONUS,
+            MUTABLE_SALE,
+            NO_TX_CAP,
+            NO_COOLDOWN,
+            NO_LAUNCH_GUARD,
+            NO_LOT_SIZE,
+            DEFAULT_QUOTE_SYMBOL,
+            DEFAULT_QUOTE_DECIMALS,
+            DEFAULT_ROUNDING
           )
           .accounts({
             sale: invalidSalePda,
             authority: authority.publicKey,
             tokenMint: tokenMint,
-            treasury: treasury.publicKey,
+            treasury: findTreasuryPda(invalidSalePda),
             systemProgram: web3.SystemProgram.programId,
             rent: web3.SYSVAR_RENT_PUBKEY,
           })
@@ -204,15 +449,15 @@ describe("ICO Token Sale", () => {
     });
 
     it("should allow token purchase", async () => {
-      const purchaseAmount = new BN(1000); // 1000 tokens
-      const expectedCost = purchaseAmount.mul(TOKEN_PRICE);
+      const purchaseAmount = tokens(1000); // 1000 tokens
+      const expectedCost = purchaseAmount.mul(TOKEN_PRICE).div(ONE_TOKEN);
 
       // Get initial balances
-      const initialTreasuryBalance = await connection.getBalance(treasury.publicKey);
+      const initialTreasuryBalance = await connection.getBalance(treasuryPda);
       const initialBuyerBalance = await connection.getBalance(buyer1.publicKey);
 
       const tx = await program.methods
-        .purchaseTokens(purchaseAmount)
+        .purchaseTokens(purchaseAmount, false, [], null, NO_DEADLINE, null)
         .accounts({
           sale: salePda,
           userPurchase: buyer1PurchasePda,
@@ -220,7 +465,13 @@ describe("ICO Token Sale", () => {
           tokenMint: tokenMint,
           saleTokenVault: saleTokenVault,
           buyerTokenAccount: buyer1TokenAccount,
-          treasury: treasury.publicKey,
+          treasury: treasuryPda,
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
           tokenProgram: TOKEN_PROGRAM_ID,
           associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
           systemProgram: web3.SystemProgram.programId,
@@ -246,7 +497,7 @@ describe("ICO Token Sale", () => {
       assert.equal(buyerTokenBalance.amount.toString(), purchaseAmount.toString());
 
       // Verify treasury received SOL
-      const finalTreasuryBalance = await connection.getBalance(treasury.publicKey);
+      const finalTreasuryBalance = await connection.getBalance(treasuryPda);
       assert.equal(
         finalTreasuryBalance - initialTreasuryBalance,
         expectedCost.toNumber()
@@ -254,11 +505,11 @@ describe("ICO Token Sale", () => {
     });
 
     it("should fail purchase below minimum", async () => {
-      const purchaseAmount = new BN(50); // Below minimum
+      const purchaseAmount = tokens(50); // Below minimum
 
       try {
         await program.methods
-          .purchaseTokens(purchaseAmount)
+          .purchaseTokens(purchaseAmount, false, [], null, NO_DEADLINE, null)
           .accounts({
             sale: salePda,
             userPurchase: buyer1PurchasePda,
@@ -266,7 +517,13 @@ describe("ICO Token Sale", () => {
             tokenMint: tokenMint,
             saleTokenVault: saleTokenVault,
             buyerTokenAccount: buyer1TokenAccount,
-            treasury: treasury.publicKey,
+            treasury: treasuryPda,
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            referrer: null,
+            referral: null,
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             systemProgram: web3.SystemProgram.programId,
@@ -282,11 +539,11 @@ describe("ICO Token Sale", () => {
     });
 
     it("should fail purchase above maximum per user", async () => {
-      const purchaseAmount = new BN(15000); // Above maximum
+      const purchaseAmount = tokens(15000); // Above maximum
 
       try {
         await program.methods
-          .purchaseTokens(purchaseAmount)
+          .purchaseTokens(purchaseAmount, false, [], null, NO_DEADLINE, null)
           .accounts({
             sale: salePda,
             userPurchase: buyer1PurchasePda,
@@ -294,7 +551,13 @@ describe("ICO Token Sale", () => {
             tokenMint: tokenMint,
             saleTokenVault: saleTokenVault,
             buyerTokenAccount: buyer1TokenAccount,
-            treasury: treasury.publicKey,
+            treasury: treasuryPda,
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            referrer: null,
+            referral: null,
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             systemProgram: web3.SystemProgram.programId,
@@ -310,6 +573,418 @@ describe("ICO Token Sale", () => {
     });
   });
 
+  describe("Auto-Stake", () => {
+    let buyer2TokenAccount: web3.PublicKey;
+    let buyer2PurchasePda: web3.PublicKey;
+    let buyer2StakePda: web3.PublicKey;
+    let buyer2StakeTokenAccount: web3.PublicKey;
+
+    before(async () => {
+      buyer2TokenAccount = await getAssociatedTokenAddress(
+        tokenMint,
+        buyer2.publicKey
+      );
+
+      [buyer2PurchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), salePda.toBuffer(), buyer2.publicKey.toBuffer()],
+        program.programId
+      );
+
+      [buyer2StakePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), salePda.toBuffer(), buyer2.publicKey.toBuffer()],
+        program.programId
+      );
+
+      buyer2StakeTokenAccount = await getAssociatedTokenAddress(
+        tokenMint,
+        buyer2StakePda,
+        true // allowOwnerOffCurve for PDA
+      );
+    });
+
+    it("should deliver opt-out purchases to the buyer's wallet", async () => {
+      const purchaseAmount = tokens(1000);
+
+      await program.methods
+        .purchaseTokens(purchaseAmount, false, [], null, NO_DEADLINE, null)
+        .accounts({
+          sale: salePda,
+          userPurchase: buyer2PurchasePda,
+          buyer: buyer2.publicKey,
+          tokenMint: tokenMint,
+          saleTokenVault: saleTokenVault,
+          buyerTokenAccount: buyer2TokenAccount,
+          treasury: treasuryPda,
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer2])
+        .rpc();
+
+      const buyerTokenBalance = await getAccount(connection, buyer2TokenAccount);
+      assert.equal(buyerTokenBalance.amount.toString(), purchaseAmount.toString());
+
+      const stakeInfo = await connection.getAccountInfo(buyer2StakePda);
+      assert.isNull(stakeInfo);
+    });
+
+    it("should stake opt-in purchases by the same wallet with a bonus", async () => {
+      const purchaseAmount = tokens(1000);
+      const expectedBonus = purchaseAmount.muln(STAKE_BONUS_BPS).divn(10_000);
+
+      const saleBefore = await program.account.sale.fetch(salePda);
+
+      await program.methods
+        .purchaseTokens(purchaseAmount, true, [], null, NO_DEADLINE, null)
+        .accounts({
+          sale: salePda,
+          userPurchase: buyer2PurchasePda,
+          buyer: buyer2.publicKey,
+          tokenMint: tokenMint,
+          saleTokenVault: saleTokenVault,
+          buyerTokenAccount: buyer2TokenAccount,
+          treasury: treasuryPda,
+          stakePosition: buyer2StakePda,
+          stakeTokenAccount: buyer2StakeTokenAccount,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer2])
+        .rpc();
+
+      // Wallet balance is unchanged; the purchase and bonus sit in the lockup
+      const buyerTokenBalance = await getAccount(connection, buyer2TokenAccount);
+      assert.equal(buyerTokenBalance.amount.toString(), tokens(1000).toString());
+
+      const stakeTokenBalance = await getAccount(connection, buyer2StakeTokenAccount);
+      assert.equal(
+        stakeTokenBalance.amount.toString(),
+        purchaseAmount.add(expectedBonus).toString()
+      );
+
+      const stake = await program.account.stakePosition.fetch(buyer2StakePda);
+      assert.equal(stake.owner.toString(), buyer2.publicKey.toString());
+      assert.equal(stake.bonusAmount.toString(), expectedBonus.toString());
+      assert.equal(stake.multiplierBps, STAKE_MULTIPLIER_BPS);
+
+      // The bonus counts against the sale supply, but not the buyer's limit
+      const saleAfter = await program.account.sale.fetch(salePda);
+      assert.equal(
+        saleAfter.stakeBonusAllocated.sub(saleBefore.stakeBonusAllocated).toString(),
+        expectedBonus.toString()
+      );
+
+      const userPurchase = await program.account.userPurchase.fetch(buyer2PurchasePda);
+      assert.equal(userPurchase.tokensPurchased.toString(), tokens(2000).toString());
+    });
+
+    it("should reject unstaking before the lockup expires", async () => {
+      try {
+        await program.methods
+          .unstake()
+          .accounts({
+            sale: salePda,
+            stakePosition: buyer2StakePda,
+            owner: buyer2.publicKey,
+            tokenMint: tokenMint,
+            stakeTokenAccount: buyer2StakeTokenAccount,
+            ownerTokenAccount: buyer2TokenAccount,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .signers([buyer2])
+          .rpc();
+
+        assert.fail("Should have failed while stake is locked");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Staked tokens are still locked");
+      }
+    });
+  });
+
+  describe("SPL Payments", () => {
+    const USDC_DECIMALS = 6;
+    const TOKEN_PRICE_USD = new BN(333_333); // $0.00333333 with 8 decimals
+
+    let usdcMint: web3.PublicKey;
+    let buyer1UsdcAccount: web3.PublicKey;
+    let treasuryUsdcAccount: web3.PublicKey;
+    let buyer1TokenAccount: web3.PublicKey;
+    let buyer1PurchasePda: web3.PublicKey;
+
+    before(async () => {
+      usdcMint = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        USDC_DECIMALS
+      );
+
+      buyer1UsdcAccount = await createAssociatedTokenAccount(
+        connection,
+        authority,
+        usdcMint,
+        buyer1.publicKey
+      );
+
+      treasuryUsdcAccount = await createAssociatedTokenAccount(
+        connection,
+        authority,
+        usdcMint,
+        paymentWallet.publicKey
+      );
+
+      await mintTo(
+        connection,
+        authority,
+        usdcMint,
+        buyer1UsdcAccount,
+        authority.publicKey,
+        1_000_000_000 // 1,000 USDC
+      );
+
+      buyer1TokenAccount = await getAssociatedTokenAddress(
+        tokenMint,
+        buyer1.publicKey
+      );
+
+      [buyer1PurchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), salePda.toBuffer(), buyer1.publicKey.toBuffer()],
+        program.programId
+      );
+    });
+
+    it("should set the payment mint", async () => {
+      await program.methods
+        .setPaymentMint(TOKEN_PRICE_USD)
+        .accounts({
+          sale: salePda,
+          authority: authority.publicKey,
+          paymentMint: usdcMint,
+          paymentTreasury: treasuryUsdcAccount,
+        })
+        .signers([authority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(salePda);
+      assert.equal(saleAccount.paymentMint.toString(), usdcMint.toString());
+      assert.equal(
+        saleAccount.paymentTreasuryAta.toString(),
+        treasuryUsdcAccount.toString()
+      );
+      assert.equal(saleAccount.tokenPriceUsd.toString(), TOKEN_PRICE_USD.toString());
+    });
+
+    it("should purchase tokens with USDC, rounding the cost up", async () => {
+      const purchaseAmount = tokens(101);
+      // 101 * 0.00333333 USD = 0.33666633 USD -> 336_667 USDC base units
+      const expectedCost = new BN(336_667);
+
+      const saleBefore = await program.account.sale.fetch(salePda);
+      const purchaseBefore = await program.account.userPurchase.fetch(buyer1PurchasePda);
+      const tokensBefore = (await getAccount(connection, buyer1TokenAccount)).amount;
+
+      await program.methods
+        .purchaseTokensWithSpl(purchaseAmount, [])
+        .accounts({
+          sale: salePda,
+          userPurchase: buyer1PurchasePda,
+          buyer: buyer1.publicKey,
+          tokenMint: tokenMint,
+          saleTokenVault: saleTokenVault,
+          buyerTokenAccount: buyer1TokenAccount,
+          paymentMint: usdcMint,
+          buyerPaymentAccount: buyer1UsdcAccount,
+          paymentTreasury: treasuryUsdcAccount,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer1])
+        .rpc();
+
+      const treasuryUsdc = await getAccount(connection, treasuryUsdcAccount);
+      assert.equal(treasuryUsdc.amount.toString(), expectedCost.toString());
+
+      const tokensAfter = (await getAccount(connection, buyer1TokenAccount)).amount;
+      assert.equal((tokensAfter - tokensBefore).toString(), purchaseAmount.toString());
+
+      // SOL and USD proceeds are tracked separately
+      const saleAfter = await program.account.sale.fetch(salePda);
+      assert.equal(saleAfter.totalRaisedUsd.toString(), expectedCost.toString());
+      assert.equal(saleAfter.totalRaised.toString(), saleBefore.totalRaised.toString());
+      assert.equal(
+        saleAfter.tokensSold.sub(saleBefore.tokensSold).toString(),
+        purchaseAmount.toString()
+      );
+
+      const purchaseAfter = await program.account.userPurchase.fetch(buyer1PurchasePda);
+      assert.equal(purchaseAfter.usdContributed.toString(), expectedCost.toString());
+      assert.equal(
+        purchaseAfter.solContributed.toString(),
+        purchaseBefore.solContributed.toString()
+      );
+    });
+
+    it("should reject a payment account with a different mint", async () => {
+      try {
+        await program.methods
+          .purchaseTokensWithSpl(tokens(100), [])
+          .accounts({
+            sale: salePda,
+            userPurchase: buyer1PurchasePda,
+            buyer: buyer1.publicKey,
+            tokenMint: tokenMint,
+            saleTokenVault: saleTokenVault,
+            buyerTokenAccount: buyer1TokenAccount,
+            paymentMint: usdcMint,
+            buyerPaymentAccount: buyer1TokenAccount, // sale token, not USDC
+            paymentTreasury: treasuryUsdcAccount,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+            rent: web3.SYSVAR_RENT_PUBKEY,
+          })
+          .signers([buyer1])
+          .rpc();
+
+        assert.fail("Should have failed with invalid payment mint");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Invalid payment mint");
+      }
+    });
+  });
+
+  describe("Remaining Allowance", () => {
+    let buyer2TokenAccount: web3.PublicKey;
+    let buyer2PurchasePda: web3.PublicKey;
+
+    const expectedAllowanceLog = async () => {
+      const saleAccount = await program.account.sale.fetch(salePda);
+      const userPurchase = await program.account.userPurchase.fetch(buyer2PurchasePda);
+      const walletRemaining = saleAccount.maxPurchase.sub(userPurchase.tokensPurchased);
+      const supplyRemaining = saleAccount.maxTokens
+        .sub(saleAccount.tokensSold)
+        .sub(saleAccount.stakeBonusAllocated);
+      const allowance = BN.min(walletRemaining, supplyRemaining);
+      return {
+        allowance,
+        line: `remaining_allowance=${allowance} wallet_remaining=${walletRemaining} supply_remaining=${supplyRemaining}`,
+      };
+    };
+
+    const purchaseAsBuyer2 = (amount: BN) =>
+      program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts({
+          sale: salePda,
+          userPurchase: buyer2PurchasePda,
+          buyer: buyer2.publicKey,
+          tokenMint: tokenMint,
+          saleTokenVault: saleTokenVault,
+          buyerTokenAccount: buyer2TokenAccount,
+          treasury: treasuryPda,
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer2])
+        .rpc();
+
+    before(async () => {
+      buyer2TokenAccount = await getAssociatedTokenAddress(
+        tokenMint,
+        buyer2.publicKey
+      );
+
+      [buyer2PurchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), salePda.toBuffer(), buyer2.publicKey.toBuffer()],
+        program.programId
+      );
+    });
+
+    it("should log the remaining allowance when exceeding the per-purchase maximum", async () => {
+      const expected = await expectedAllowanceLog();
+
+      try {
+        await purchaseAsBuyer2(MAX_PURCHASE.addn(1));
+        assert.fail("Should have failed with exceeds maximum purchase");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds maximum purchase amount");
+        expect(error.logs.some((log: string) => log.includes(expected.line))).to.be.true;
+      }
+    });
+
+    it("should log the remaining allowance when exceeding the wallet limit", async () => {
+      const expected = await expectedAllowanceLog();
+
+      try {
+        // Within the per-purchase maximum, but over the wallet's lifetime limit
+        await purchaseAsBuyer2(expected.allowance.addn(1));
+        assert.fail("Should have failed with exceeds user limit");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds user purchase limit");
+        expect(error.logs.some((log: string) => log.includes(expected.line))).to.be.true;
+      }
+    });
+
+    it("should report the same allowance from the user info view", async () => {
+      const expected = await expectedAllowanceLog();
+
+      const info = await program.methods
+        .getUserInfo()
+        .accounts({
+          sale: salePda,
+          user: buyer2.publicKey,
+          userPurchase: buyer2PurchasePda,
+        })
+        .view();
+
+      assert.equal(info.remainingAllowance.toString(), expected.allowance.toString());
+
+      // Wallets without a purchase record get the full per-wallet limit
+      const freshInfo = await program.methods
+        .getUserInfo()
+        .accounts({
+          sale: salePda,
+          user: web3.Keypair.generate().publicKey,
+          userPurchase: null,
+        })
+        .view();
+
+      assert.equal(freshInfo.tokensPurchased.toString(), "0");
+      assert.equal(freshInfo.remainingAllowance.toString(), MAX_PURCHASE.toString());
+    });
+  });
+
   describe("Sale Management", () => {
     it("should pause and unpause sale", async () => {
       // Pause sale
@@ -337,7 +1012,7 @@ describe("ICO Token Sale", () => {
 
       try {
         await program.methods
-          .purchaseTokens(new BN(500))
+          .purchaseTokens(tokens(500), false, [], null, NO_DEADLINE, null)
           .accounts({
             sale: salePda,
             userPurchase: buyer2PurchasePda,
@@ -345,7 +1020,13 @@ describe("ICO Token Sale", () => {
             tokenMint: tokenMint,
             saleTokenVault: saleTokenVault,
             buyerTokenAccount: buyer2TokenAccount,
-            treasury: treasury.publicKey,
+            treasury: treasuryPda,
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            referrer: null,
+            referral: null,
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             systemProgram: web3.SystemProgram.programId,
@@ -403,13 +1084,30 @@ describe("ICO Token Sale", () => {
       const saleAccount = await program.account.sale.fetch(salePda);
       assert.equal(saleAccount.isActive, false);
     });
-  });
 
-  describe("Withdraw Remaining Tokens", () => {
-    it("should withdraw remaining tokens after sale ends", async () => {
-      // Get initial authority token balance
-      const initialBalance = await getAccount(connection, authorityTokenAccount);
-      const initialAmount = initialBalance.amount;
+    it("should not move the end time later when ended again", async () => {
+      const { endTime } = await program.account.sale.fetch(salePda);
+      await new Promise(resolve => setTimeout(resolve, 2000));
+
+      await program.methods
+        .endSale()
+        .accounts({
+          sale: salePda,
+          authority: authority.publicKey,
+        })
+        .signers([authority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(salePda);
+      assert.equal(saleAccount.endTime.toString(), endTime.toString());
+    });
+  });
+
+  describe("Withdraw Remaining Tokens", () => {
+    it("should withdraw remaining tokens after sale ends", async () => {
+      // Get initial authority token balance
+      const initialBalance = await getAccount(connection, authorityTokenAccount);
+      const initialAmount = initialBalance.amount;
 
       // Get remaining tokens in vault
       const vaultBalance = await getAccount(connection, saleTokenVault);
@@ -462,13 +1160,30 @@ describe("ICO Token Sale", () => {
           MAX_TOKENS,
           MIN_PURCHASE,
           MAX_PURCHASE,
-          new BN(7200) // 2 hours from now
+          SALE_DURATION,
+          new BN(7200), // starts 2 hours from now
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
         )
         .accounts({
           sale: newSalePda,
           authority: newAuthority.publicKey,
           tokenMint: tokenMint,
-          treasury: treasury.publicKey,
+          treasury: findTreasuryPda(newSalePda),
           systemProgram: web3.SystemProgram.programId,
           rent: web3.SYSVAR_RENT_PUBKEY,
         })
@@ -481,7 +1196,10 @@ describe("ICO Token Sale", () => {
           new BN(2_000_000), // new price
           new BN(500_000),   // new max tokens
           new BN(200),       // new min purchase
-          new BN(5000)       // new max purchase
+          new BN(5000),      // new max purchase
+          null,              // keep max purchase per transaction
+          null,              // keep cooldown
+          null               // keep launch guard
         )
         .accounts({
           sale: newSalePda,
@@ -502,6 +1220,49 @@ describe("ICO Token Sale", () => {
       // Test is implemented in the before block above
       assert.ok("Parameters updated successfully");
     });
+
+    const updateParams = (
+      sale: StandaloneSale,
+      maxTokens: BN | null,
+      minPurchase: BN | null
+    ) =>
+      program.methods
+        .updateSaleParams(null, maxTokens, minPurchase, null, null, null, null)
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    it("should not lower max tokens below the soft cap", async () => {
+      const sale = await createFundedSale({ softCap: tokens(500), startDelay: new BN(3600) });
+
+      try {
+        await updateParams(sale, tokens(400), null);
+        assert.fail("Should have failed with max tokens below the soft cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Soft cap cannot exceed max tokens");
+      }
+
+      await updateParams(sale, tokens(500), null);
+      const saleAccount = await program.account.sale.fetch(sale.salePda);
+      assert.equal(saleAccount.maxTokens.toString(), tokens(500).toString());
+    });
+
+    it("should not raise min purchase above the allowlist cap", async () => {
+      const sale = await createFundedSale({ startDelay: new BN(3600) });
+      const { startTime } = await program.account.sale.fetch(sale.salePda);
+      await program.methods
+        .setAllowlist(Array(32).fill(1), startTime.addn(60), MIN_PURCHASE)
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+      try {
+        await updateParams(sale, null, tokens(200));
+        assert.fail("Should have failed with min purchase above the whitelist cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Invalid purchase limits");
+      }
+    });
   });
 
  
@@ -518,7 +1279,7 @@ describe("ICO Token Sale", () => {
         program.programId
       );
 
-      const smallMaxTokens = new BN(1000);
+      const smallMaxTokens = tokens(1000);
       
       await program.methods
         .initializeSale(
@@ -526,13 +1287,30 @@ describe("ICO Token Sale", () => {
           smallMaxTokens,
           MIN_PURCHASE,
           MAX_PURCHASE,
-          SALE_DURATION
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
         )
         .accounts({
           sale: edgeSalePda,
           authority: edgeAuthority.publicKey,
           tokenMint: tokenMint,
-          treasury: treasury.publicKey,
+          treasury: findTreasuryPda(edgeSalePda),
           systemProgram: web3.SystemProgram.programId,
           rent: web3.SYSVAR_RENT_PUBKEY,
         })
@@ -572,7 +1350,7 @@ describe("ICO Token Sale", () => {
 
       try {
         await program.methods
-          .purchaseTokens(new BN(1500)) // More than max tokens
+          .purchaseTokens(tokens(1500), false, [], null, NO_DEADLINE, null) // More than max tokens
           .accounts({
             sale: edgeSalePda,
             userPurchase: buyer3PurchasePda,
@@ -580,7 +1358,13 @@ describe("ICO Token Sale", () => {
             tokenMint: tokenMint,
             saleTokenVault: edgeSaleVault,
             buyerTokenAccount: buyer3TokenAccount,
-            treasury: treasury.publicKey,
+            treasury: findTreasuryPda(edgeSalePda),
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            referrer: null,
+            referral: null,
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             systemProgram: web3.SystemProgram.programId,
@@ -592,7 +1376,3363 @@ describe("ICO Token Sale", () => {
         assert.fail("Should have failed with exceeds max tokens");
       } catch (error) {
         expect(error.error.errorMessage).to.include("Exceeds maximum tokens for sale");
+        expect(
+          error.logs.some((log: string) =>
+            log.includes(
+              `remaining_allowance=${tokens(1000)} wallet_remaining=${tokens(10_000)} supply_remaining=${tokens(1000)}`
+            )
+          )
+        ).to.be.true;
+      }
+    });
+  });
+
+  describe("Token Decimals", () => {
+    const setupSale = async (
+      decimals: number,
+      price: BN,
+      maxTokens: BN
+    ) => {
+      const saleAuthority = web3.Keypair.generate();
+      await connection.requestAirdrop(saleAuthority.publicKey, 5 * web3.LAMPORTS_PER_SOL);
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      const mint = await createMint(
+        connection,
+        saleAuthority,
+        saleAuthority.publicKey,
+        null,
+        decimals
+      );
+
+      const [decimalsSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), saleAuthority.publicKey.toBuffer(), mint.toBuffer()],
+        program.programId
+      );
+
+      await program.methods
+        .initializeSale(
+          price,
+          maxTokens,
+          new BN(1), // one base unit minimum
+          maxTokens,
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: decimalsSalePda,
+          authority: saleAuthority.publicKey,
+          tokenMint: mint,
+          treasury: findTreasuryPda(decimalsSalePda),
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([saleAuthority])
+        .rpc();
+
+      const vault = await getAssociatedTokenAddress(mint, decimalsSalePda, true);
+      await mintTo(
+        connection,
+        saleAuthority,
+        mint,
+        vault,
+        saleAuthority.publicKey,
+        BigInt(maxTokens.toString())
+      );
+
+      return { mint, salePda: decimalsSalePda, vault };
+    };
+
+    const purchaseAndMeasureCost = async (
+      sale: { mint: web3.PublicKey; salePda: web3.PublicKey; vault: web3.PublicKey },
+      amount: BN,
+      airdropSol: number
+    ) => {
+      const buyer = web3.Keypair.generate();
+      await connection.requestAirdrop(buyer.publicKey, airdropSol * web3.LAMPORTS_PER_SOL);
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      const [purchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), sale.salePda.toBuffer(), buyer.publicKey.toBuffer()],
+        program.programId
+      );
+
+      const saleTreasury = findTreasuryPda(sale.salePda);
+      const initialTreasuryBalance = await connection.getBalance(saleTreasury);
+
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: purchasePda,
+          buyer: buyer.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: await getAssociatedTokenAddress(sale.mint, buyer.publicKey),
+          treasury: saleTreasury,
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer])
+        .rpc();
+
+      const finalTreasuryBalance = await connection.getBalance(saleTreasury);
+      return finalTreasuryBalance - initialTreasuryBalance;
+    };
+
+    it("should charge exactly the token price for one whole token of a 0-decimal mint", async () => {
+      const sale = await setupSale(0, TOKEN_PRICE, new BN(1_000));
+      const cost = await purchaseAndMeasureCost(sale, new BN(1), 1);
+      assert.equal(cost, TOKEN_PRICE.toNumber());
+    });
+
+    it("should charge exactly the token price for one whole token of a 6-decimal mint", async () => {
+      const sale = await setupSale(6, TOKEN_PRICE, new BN(1_000_000_000));
+      const cost = await purchaseAndMeasureCost(sale, new BN(1_000_000), 1);
+      assert.equal(cost, TOKEN_PRICE.toNumber());
+
+      // Fractional amounts are priced proportionally
+      const fractionalCost = await purchaseAndMeasureCost(sale, new BN(1_500_000), 1);
+      assert.equal(fractionalCost, TOKEN_PRICE.muln(3).divn(2).toNumber());
+    });
+
+    it("should charge exactly the token price for one whole token of a 9-decimal mint", async () => {
+      const sale = await setupSale(9, TOKEN_PRICE, tokens(1_000));
+      const cost = await purchaseAndMeasureCost(sale, ONE_TOKEN, 1);
+      assert.equal(cost, TOKEN_PRICE.toNumber());
+    });
+
+    it("should price amounts whose product overflows u64", async () => {
+      // 1,000 tokens at 0.02 SOL: 10^12 * 2*10^7 = 2*10^19 > u64::MAX
+      const price = new BN(20_000_000);
+      const amount = tokens(1_000);
+      const sale = await setupSale(9, price, tokens(10_000));
+
+      const cost = await purchaseAndMeasureCost(sale, amount, 25);
+      assert.equal(cost, 20 * web3.LAMPORTS_PER_SOL);
+    });
+  });
+
+  describe("Soft Cap & Refunds", () => {
+    const SOFT_CAP = tokens(500);
+
+    let capAuthority: web3.Keypair;
+    let capMint: web3.PublicKey;
+    let capSalePda: web3.PublicKey;
+    let capTreasury: web3.PublicKey;
+    let capVault: web3.PublicKey;
+    let capBuyer: web3.Keypair;
+    let capBuyerTokenAccount: web3.PublicKey;
+    let capBuyerPurchasePda: web3.PublicKey;
+    let capStaker: web3.Keypair;
+    let capStakerPurchasePda: web3.PublicKey;
+    let capStakerStakePda: web3.PublicKey;
+
+    const purchasePda = (sale: web3.PublicKey, buyer: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), sale.toBuffer(), buyer.toBuffer()],
+        program.programId
+      )[0];
+
+    before(async () => {
+      capAuthority = web3.Keypair.generate();
+      capBuyer = web3.Keypair.generate();
+      capStaker = web3.Keypair.generate();
+      await Promise.all([
+        connection.requestAirdrop(capAuthority.publicKey, 5 * web3.LAMPORTS_PER_SOL),
+        connection.requestAirdrop(capBuyer.publicKey, 5 * web3.LAMPORTS_PER_SOL),
+        connection.requestAirdrop(capStaker.publicKey, 5 * web3.LAMPORTS_PER_SOL),
+      ]);
+      await new Promise(resolve => setTimeout(resolve, 2000));
+
+      capMint = await createMint(
+        connection,
+        capAuthority,
+        capAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+
+      [capSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), capAuthority.publicKey.toBuffer(), capMint.toBuffer()],
+        program.programId
+      );
+      capTreasury = findTreasuryPda(capSalePda);
+      capVault = await getAssociatedTokenAddress(capMint, capSalePda, true);
+      capBuyerTokenAccount = await getAssociatedTokenAddress(capMint, capBuyer.publicKey);
+      capBuyerPurchasePda = purchasePda(capSalePda, capBuyer.publicKey);
+      capStakerPurchasePda = purchasePda(capSalePda, capStaker.publicKey);
+      [capStakerStakePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), capSalePda.toBuffer(), capStaker.publicKey.toBuffer()],
+        program.programId
+      );
+
+      await program.methods
+        .initializeSale(
+          TOKEN_PRICE,
+          tokens(1_000),
+          tokens(1),
+          tokens(1_000),
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: capSalePda,
+          authority: capAuthority.publicKey,
+          tokenMint: capMint,
+          treasury: capTreasury,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([capAuthority])
+        .rpc();
+
+      await mintTo(
+        connection,
+        capAuthority,
+        capMint,
+        capVault,
+        capAuthority.publicKey,
+        BigInt(tokens(1_000).toString())
+      );
+
+      // One plain purchase and one auto-staked purchase, both below the soft cap
+      for (const [buyer, autoStake] of [
+        [capBuyer, false],
+        [capStaker, true],
+      ] as [web3.Keypair, boolean][]) {
+        const stakePda = autoStake ? capStakerStakePda : null;
+        await program.methods
+          .purchaseTokens(tokens(100), autoStake, [], null, NO_DEADLINE, null)
+          .accounts({
+            sale: capSalePda,
+            userPurchase: purchasePda(capSalePda, buyer.publicKey),
+            buyer: buyer.publicKey,
+            tokenMint: capMint,
+            saleTokenVault: capVault,
+            buyerTokenAccount: await getAssociatedTokenAddress(capMint, buyer.publicKey),
+            treasury: capTreasury,
+            stakePosition: stakePda,
+            stakeTokenAccount: stakePda
+              ? await getAssociatedTokenAddress(capMint, stakePda, true)
+              : null,
+            aggregateConfig: null,
+            aggregateLimit: null,
+            referrer: null,
+            referral: null,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+            rent: web3.SYSVAR_RENT_PUBKEY,
+          })
+          .signers([buyer])
+          .rpc();
+      }
+    });
+
+    const claimRefund = (
+      buyer: web3.Keypair,
+      stakePosition: web3.PublicKey | null = null,
+      stakeTokenAccount: web3.PublicKey | null = null
+    ) =>
+      program.methods
+        .claimRefund()
+        .accounts({
+          sale: capSalePda,
+          userPurchase: purchasePda(capSalePda, buyer.publicKey),
+          buyer: buyer.publicKey,
+          tokenMint: capMint,
+          saleTokenVault: capVault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(capMint, buyer.publicKey),
+          treasury: capTreasury,
+          stakePosition,
+          stakeTokenAccount,
+          aggregateLimit: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([buyer])
+        .rpc();
+
+    it("should not finalize before the sale ends", async () => {
+      try {
+        await program.methods.finalizeSale().accounts({ sale: capSalePda }).rpc();
+        assert.fail("Should have failed while sale is active");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale is still active");
+      }
+    });
+
+    it("should not refund before the sale is finalized", async () => {
+      try {
+        await claimRefund(capBuyer);
+        assert.fail("Should have failed before finalization");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Refunds are only available for failed sales");
+      }
+    });
+
+    it("should finalize as failed when the soft cap is missed", async () => {
+      await program.methods
+        .endSale()
+        .accounts({ sale: capSalePda, authority: capAuthority.publicKey })
+        .signers([capAuthority])
+        .rpc();
+
+      await program.methods.finalizeSale().accounts({ sale: capSalePda }).rpc();
+
+      const saleAccount = await program.account.sale.fetch(capSalePda);
+      assert.deepEqual(saleAccount.outcome, { failed: {} });
+
+      try {
+        await program.methods.finalizeSale().accounts({ sale: capSalePda }).rpc();
+        assert.fail("Should have failed when finalizing twice");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale has already been finalized");
+      }
+    });
+
+    it("should refund SOL and reclaim tokens", async () => {
+      const userPurchase = await program.account.userPurchase.fetch(capBuyerPurchasePda);
+      const treasuryBefore = await connection.getBalance(capTreasury);
+      const vaultBefore = (await getAccount(connection, capVault)).amount;
+
+      await claimRefund(capBuyer);
+
+      const treasuryAfter = await connection.getBalance(capTreasury);
+      assert.equal(
+        treasuryBefore - treasuryAfter,
+        userPurchase.solContributed.toNumber()
+      );
+
+      const vaultAfter = (await getAccount(connection, capVault)).amount;
+      assert.equal(
+        (vaultAfter - vaultBefore).toString(),
+        userPurchase.tokensPurchased.toString()
+      );
+
+      const buyerTokens = await getAccount(connection, capBuyerTokenAccount);
+      assert.equal(buyerTokens.amount.toString(), "0");
+
+      const refunded = await program.account.userPurchase.fetch(capBuyerPurchasePda);
+      assert.equal(refunded.refunded, true);
+    });
+
+    it("should reject a second refund claim", async () => {
+      try {
+        await claimRefund(capBuyer);
+        assert.fail("Should have failed on double claim");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Purchase has already been refunded");
+      }
+    });
+
+    it("should require the stake accounts to refund a staked purchase", async () => {
+      try {
+        await claimRefund(capStaker);
+        assert.fail("Should have failed without the stake accounts");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Stake accounts are required");
+      }
+    });
+
+    it("should unwind an auto-stake position when refunding", async () => {
+      const stakeTokenAccount = await getAssociatedTokenAddress(
+        capMint,
+        capStakerStakePda,
+        true
+      );
+      const stake = await program.account.stakePosition.fetch(capStakerStakePda);
+      const vaultBefore = (await getAccount(connection, capVault)).amount;
+
+      await claimRefund(capStaker, capStakerStakePda, stakeTokenAccount);
+
+      // Purchase and bonus both return to the vault, and the bonus is released
+      const vaultAfter = (await getAccount(connection, capVault)).amount;
+      assert.equal((vaultAfter - vaultBefore).toString(), stake.amount.toString());
+      assert.isNull(await connection.getAccountInfo(capStakerStakePda));
+
+      const saleAccount = await program.account.sale.fetch(capSalePda);
+      assert.equal(saleAccount.stakeBonusAllocated.toString(), "0");
+    });
+
+    it("should keep an expired lockup staked until the refund unwinds it", async () => {
+      const sale = await createFundedSale({ softCap: tokens(900), stakeLockDuration: new BN(1) });
+      const staker = await fundedWallet();
+      const [stakePosition] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), sale.salePda.toBuffer(), staker.publicKey.toBuffer()],
+        program.programId
+      );
+      const stakeTokenAccount = getAssociatedTokenAddressSync(sale.mint, stakePosition, true);
+
+      await program.methods
+        .purchaseTokens(tokens(100), true, [], null, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(sale, staker.publicKey),
+          stakePosition,
+          stakeTokenAccount,
+        })
+        .signers([staker])
+        .rpc();
+      await program.methods
+        .endSale()
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+      await program.methods.finalizeSale().accounts({ sale: sale.salePda }).rpc();
+      await new Promise(resolve => setTimeout(resolve, 2000)); // let the lockup expire
+
+      try {
+        await program.methods
+          .unstake()
+          .accounts({
+            sale: sale.salePda,
+            stakePosition,
+            owner: staker.publicKey,
+            tokenMint: sale.mint,
+            stakeTokenAccount,
+            ownerTokenAccount: getAssociatedTokenAddressSync(sale.mint, staker.publicKey),
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .signers([staker])
+          .rpc();
+        assert.fail("Should have failed on a failed soft-cap sale");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale must be finalized as successful first");
+      }
+
+      // The refund still finds the position and takes the bonus back
+      await program.methods
+        .claimRefund()
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: solPurchaseAccounts(sale, staker.publicKey).userPurchase,
+          buyer: staker.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(sale.mint, staker.publicKey),
+          treasury: findTreasuryPda(sale.salePda),
+          stakePosition,
+          stakeTokenAccount,
+          aggregateLimit: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([staker])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(sale.salePda);
+      assert.equal(saleAccount.stakeBonusAllocated.toString(), "0");
+      const vault = await getAccount(connection, sale.vault);
+      assert.equal(vault.amount.toString(), tokens(1_000).toString());
+    });
+
+    it("should reject refunds on a successful sale", async () => {
+      // The main sale has no soft cap and ended in "Sale Management"
+      await program.methods.finalizeSale().accounts({ sale: salePda }).rpc();
+
+      const saleAccount = await program.account.sale.fetch(salePda);
+      assert.deepEqual(saleAccount.outcome, { success: {} });
+
+      const [buyer1PurchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), salePda.toBuffer(), buyer1.publicKey.toBuffer()],
+        program.programId
+      );
+
+      try {
+        await program.methods
+          .claimRefund()
+          .accounts({
+            sale: salePda,
+            userPurchase: buyer1PurchasePda,
+            buyer: buyer1.publicKey,
+            tokenMint: tokenMint,
+            saleTokenVault: saleTokenVault,
+            buyerTokenAccount: getAssociatedTokenAddressSync(tokenMint, buyer1.publicKey),
+            treasury: treasuryPda,
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateLimit: null,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([buyer1])
+          .rpc();
+        assert.fail("Should have failed on a successful sale");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Refunds are only available for failed sales");
       }
     });
   });
-});
\ No newline at end of file
+
+  describe("Vesting", () => {
+    const TGE_BPS = 2_500; // 25% at claim start
+    const ALLOCATION = new BN(100); // base units, small enough that linear vesting rounds to 0
+
+    const setupVestingSale = async (cliffSeconds: BN, vestingSeconds: BN) => {
+      const vestAuthority = web3.Keypair.generate();
+      await connection.requestAirdrop(vestAuthority.publicKey, 5 * web3.LAMPORTS_PER_SOL);
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      const mint = await createMint(
+        connection,
+        vestAuthority,
+        vestAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+      const [vestSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), vestAuthority.publicKey.toBuffer(), mint.toBuffer()],
+        program.programId
+      );
+      const vault = await getAssociatedTokenAddress(mint, vestSalePda, true);
+
+      await program.methods
+        .initializeSale(
+          TOKEN_PRICE,
+          tokens(1_000),
+          new BN(1),
+          tokens(1_000),
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          TGE_BPS,
+          cliffSeconds,
+          vestingSeconds,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: vestSalePda,
+          authority: vestAuthority.publicKey,
+          tokenMint: mint,
+          treasury: findTreasuryPda(vestSalePda),
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([vestAuthority])
+        .rpc();
+
+      await mintTo(
+        connection,
+        vestAuthority,
+        mint,
+        vault,
+        vestAuthority.publicKey,
+        BigInt(tokens(1_000).toString())
+      );
+
+      const buyer = web3.Keypair.generate();
+      await connection.requestAirdrop(buyer.publicKey, 2 * web3.LAMPORTS_PER_SOL);
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      const [purchasePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), vestSalePda.toBuffer(), buyer.publicKey.toBuffer()],
+        program.programId
+      );
+
+      return {
+        authority: vestAuthority,
+        mint,
+        salePda: vestSalePda,
+        vault,
+        buyer,
+        purchasePda,
+        buyerTokenAccount: getAssociatedTokenAddressSync(mint, buyer.publicKey),
+      };
+    };
+
+    type VestingSale = Awaited<ReturnType<typeof setupVestingSale>>;
+
+    const purchase = (sale: VestingSale, amount: BN, autoStake = false) => {
+      const stakePda = autoStake
+        ? web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("stake"), sale.salePda.toBuffer(), sale.buyer.publicKey.toBuffer()],
+            program.programId
+          )[0]
+        : null;
+      return program.methods
+        .purchaseTokens(amount, autoStake, [], null, NO_DEADLINE, null)
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: sale.purchasePda,
+          buyer: sale.buyer.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: sale.buyerTokenAccount,
+          treasury: findTreasuryPda(sale.salePda),
+          stakePosition: stakePda,
+          stakeTokenAccount: stakePda
+            ? getAssociatedTokenAddressSync(sale.mint, stakePda, true)
+            : null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([sale.buyer])
+        .rpc();
+    };
+
+    const claim = (sale: VestingSale) =>
+      program.methods
+        .claimTokens()
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: sale.purchasePda,
+          buyer: sale.buyer.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: sale.buyerTokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([sale.buyer])
+        .rpc();
+
+    const endSale = (sale: VestingSale) =>
+      program.methods
+        .endSale()
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    let vestingSale: VestingSale;
+
+    before(async () => {
+      // A vesting period far longer than the test keeps the linear part at zero
+      vestingSale = await setupVestingSale(new BN(0), new BN(1_000_000_000));
+    });
+
+    it("should credit purchases without delivering tokens", async () => {
+      const vaultBefore = (await getAccount(connection, vestingSale.vault)).amount;
+
+      await purchase(vestingSale, ALLOCATION);
+
+      const vaultAfter = (await getAccount(connection, vestingSale.vault)).amount;
+      assert.equal(vaultAfter.toString(), vaultBefore.toString());
+
+      const userPurchase = await program.account.userPurchase.fetch(vestingSale.purchasePda);
+      assert.equal(userPurchase.tokensPurchased.toString(), ALLOCATION.toString());
+      assert.equal(userPurchase.tokensClaimed.toString(), "0");
+
+      const saleAccount = await program.account.sale.fetch(vestingSale.salePda);
+      assert.equal(saleAccount.tokensOwed.toString(), ALLOCATION.toString());
+    });
+
+    it("should reject auto-staking on a vesting sale", async () => {
+      try {
+        await purchase(vestingSale, ALLOCATION, true);
+        assert.fail("Should have failed with staking on a vesting sale");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Auto-staking requires instant token delivery");
+      }
+    });
+
+    it("should reject claims while the sale is active", async () => {
+      try {
+        await claim(vestingSale);
+        assert.fail("Should have failed while sale is active");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale is still active");
+      }
+    });
+
+    it("should release the TGE share once the sale ends", async () => {
+      await endSale(vestingSale);
+      await claim(vestingSale);
+
+      const expectedTge = ALLOCATION.muln(TGE_BPS).divn(10_000);
+      const buyerTokens = await getAccount(connection, vestingSale.buyerTokenAccount);
+      assert.equal(buyerTokens.amount.toString(), expectedTge.toString());
+
+      const userPurchase = await program.account.userPurchase.fetch(vestingSale.purchasePda);
+      assert.equal(userPurchase.tokensClaimed.toString(), expectedTge.toString());
+
+      const saleAccount = await program.account.sale.fetch(vestingSale.salePda);
+      assert.equal(
+        saleAccount.tokensOwed.toString(),
+        ALLOCATION.sub(expectedTge).toString()
+      );
+    });
+
+    it("should reject a claim when nothing new has vested", async () => {
+      try {
+        await claim(vestingSale);
+        assert.fail("Should have failed with nothing to claim");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("No newly vested tokens to claim");
+      }
+    });
+
+    it("should not let the authority sweep owed tokens", async () => {
+      const authorityTokenAccount = await createAssociatedTokenAccount(
+        connection,
+        vestingSale.authority,
+        vestingSale.mint,
+        vestingSale.authority.publicKey
+      );
+
+      await program.methods
+        .withdrawRemainingTokens()
+        .accounts({
+          sale: vestingSale.salePda,
+          authority: vestingSale.authority.publicKey,
+          tokenMint: vestingSale.mint,
+          saleTokenVault: vestingSale.vault,
+          authorityTokenAccount: authorityTokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([vestingSale.authority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(vestingSale.salePda);
+      const vault = await getAccount(connection, vestingSale.vault);
+      assert.equal(vault.amount.toString(), saleAccount.tokensOwed.toString());
+    });
+
+    it("should reject claims before the cliff", async () => {
+      const cliffSale = await setupVestingSale(new BN(3600), new BN(1_000_000_000));
+      await purchase(cliffSale, ALLOCATION);
+      await endSale(cliffSale);
+
+      try {
+        await claim(cliffSale);
+        assert.fail("Should have failed before the cliff");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Tokens cannot be claimed before the cliff");
+      }
+    });
+  });
+
+  describe("Allowlist", () => {
+    const START_DELAY = 6; // seconds until the allowlist sales open
+    const WHITELIST_WINDOW = 8; // seconds after start that only allowlisted wallets may buy
+    const WHITELIST_MAX_PURCHASE = tokens(200);
+
+    // Allowlist leaves are keccak(wallet)
+    const buildMerkleTree = (wallets: web3.PublicKey[]) => {
+      const tree = merkleTree(wallets.map(wallet => Buffer.from(keccak_256(wallet.toBuffer()))));
+      return {
+        root: tree.root,
+        proof: (wallet: web3.PublicKey) => tree.proof(wallets.findIndex(w => w.equals(wallet))),
+      };
+    };
+
+    const setupDelayedSale = async () => {
+      const saleAuthority = web3.Keypair.generate();
+      await connection.requestAirdrop(saleAuthority.publicKey, 5 * web3.LAMPORTS_PER_SOL);
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      const mint = await createMint(
+        connection,
+        saleAuthority,
+        saleAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+      const [delayedSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), saleAuthority.publicKey.toBuffer(), mint.toBuffer()],
+        program.programId
+      );
+      const vault = await getAssociatedTokenAddress(mint, delayedSalePda, true);
+
+      await program.methods
+        .initializeSale(
+          TOKEN_PRICE,
+          tokens(10_000),
+          MIN_PURCHASE,
+          tokens(1_000),
+          SALE_DURATION,
+          new BN(START_DELAY),
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: delayedSalePda,
+          authority: saleAuthority.publicKey,
+          tokenMint: mint,
+          treasury: findTreasuryPda(delayedSalePda),
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([saleAuthority])
+        .rpc();
+
+      await mintTo(
+        connection,
+        saleAuthority,
+        mint,
+        vault,
+        saleAuthority.publicKey,
+        BigInt(tokens(10_000).toString())
+      );
+
+      return { authority: saleAuthority, mint, salePda: delayedSalePda, vault };
+    };
+
+    type DelayedSale = Awaited<ReturnType<typeof setupDelayedSale>>;
+
+    const setAllowlist = (sale: DelayedSale, root: number[], whitelistEndTime: BN) =>
+      program.methods
+        .setAllowlist(root, whitelistEndTime, WHITELIST_MAX_PURCHASE)
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    const purchase = (sale: DelayedSale, buyer: web3.Keypair, amount: BN, proof: number[][]) =>
+      program.methods
+        .purchaseTokens(amount, false, proof, null, NO_DEADLINE, null)
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("purchase"), sale.salePda.toBuffer(), buyer.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          buyer: buyer.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(sale.mint, buyer.publicKey),
+          treasury: findTreasuryPda(sale.salePda),
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer])
+        .rpc();
+
+    // Five members so the tree has an unpaired node to carry up
+    const members = Array.from({ length: 5 }, () => web3.Keypair.generate());
+    const outsider = web3.Keypair.generate();
+    const memberTree = buildMerkleTree(members.map(m => m.publicKey));
+    // A different sale's tree that does include the outsider
+    const otherTree = buildMerkleTree([
+      outsider.publicKey,
+      ...Array.from({ length: 3 }, () => web3.Keypair.generate().publicKey),
+    ]);
+
+    let allowlistSale: DelayedSale;
+    let otherSale: DelayedSale;
+    let startTime: number;
+    let whitelistEndTime: number;
+
+    before(async () => {
+      await Promise.all(
+        [...members, outsider].map(wallet =>
+          connection.requestAirdrop(wallet.publicKey, 2 * web3.LAMPORTS_PER_SOL)
+        )
+      );
+      [allowlistSale, otherSale] = await Promise.all([setupDelayedSale(), setupDelayedSale()]);
+
+      const saleAccount = await program.account.sale.fetch(allowlistSale.salePda);
+      startTime = saleAccount.startTime.toNumber();
+      whitelistEndTime = startTime + WHITELIST_WINDOW;
+    });
+
+    it("should set the allowlist before the sale starts", async () => {
+      await setAllowlist(allowlistSale, memberTree.root, new BN(whitelistEndTime));
+      const otherStart = (await program.account.sale.fetch(otherSale.salePda)).startTime;
+      await setAllowlist(otherSale, otherTree.root, otherStart.addn(WHITELIST_WINDOW));
+
+      const saleAccount = await program.account.sale.fetch(allowlistSale.salePda);
+      assert.deepEqual(Array.from(saleAccount.whitelistRoot), memberTree.root);
+      assert.equal(saleAccount.whitelistEndTime.toNumber(), whitelistEndTime);
+      assert.equal(
+        saleAccount.whitelistMaxPurchase.toString(),
+        WHITELIST_MAX_PURCHASE.toString()
+      );
+    });
+
+    it("should accept an allowlisted wallet with a valid proof", async () => {
+      await waitForClock(startTime);
+
+      const member = members[4];
+      await purchase(allowlistSale, member, WHITELIST_MAX_PURCHASE, memberTree.proof(member.publicKey));
+
+      const buyerTokens = await getAccount(
+        connection,
+        getAssociatedTokenAddressSync(allowlistSale.mint, member.publicKey)
+      );
+      assert.equal(buyerTokens.amount.toString(), WHITELIST_MAX_PURCHASE.toString());
+    });
+
+    it("should cap allowlisted wallets at whitelist_max_purchase", async () => {
+      const member = members[4];
+      try {
+        await purchase(allowlistSale, member, MIN_PURCHASE, memberTree.proof(member.publicKey));
+        assert.fail("Should have failed above the whitelist cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds user purchase limit");
+      }
+    });
+
+    it("should reject a wallet without a proof", async () => {
+      try {
+        await purchase(allowlistSale, outsider, MIN_PURCHASE, []);
+        assert.fail("Should have failed without a proof");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Wallet is not on the sale allowlist");
+      }
+    });
+
+    it("should reject a wrong proof", async () => {
+      try {
+        // members[1]'s proof does not hash members[0]'s leaf up to the root
+        await purchase(allowlistSale, members[0], MIN_PURCHASE, memberTree.proof(members[1].publicKey));
+        assert.fail("Should have failed with a wrong proof");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Wallet is not on the sale allowlist");
+      }
+    });
+
+    it("should reject a proof for a different sale's root", async () => {
+      try {
+        await purchase(allowlistSale, outsider, MIN_PURCHASE, otherTree.proof(outsider.publicKey));
+        assert.fail("Should have failed with another sale's proof");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Wallet is not on the sale allowlist");
+      }
+    });
+
+    it("should not update the allowlist after the sale starts", async () => {
+      try {
+        await setAllowlist(allowlistSale, otherTree.root, new BN(whitelistEndTime));
+        assert.fail("Should have failed after start");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale has already started");
+      }
+    });
+
+    it("should make the proof optional after the whitelist window", async () => {
+      await waitForClock(whitelistEndTime);
+
+      // Above the whitelist cap but within the regular max_purchase
+      const amount = WHITELIST_MAX_PURCHASE.add(MIN_PURCHASE);
+      await purchase(allowlistSale, outsider, amount, []);
+
+      const buyerTokens = await getAccount(
+        connection,
+        getAssociatedTokenAddressSync(allowlistSale.mint, outsider.publicKey)
+      );
+      assert.equal(buyerTokens.amount.toString(), amount.toString());
+    });
+  });
+
+  describe("Reservations", () => {
+    const RESERVATION_TTL = 5; // seconds
+    const SUPPLY = tokens(1_000);
+
+    let resAuthority: web3.Keypair;
+    let resMint: web3.PublicKey;
+    let resSalePda: web3.PublicKey;
+    let resVault: web3.PublicKey;
+    const [holder, buyerB, buyerC, bystander] = Array.from({ length: 4 }, () =>
+      web3.Keypair.generate()
+    );
+
+    const findReservationPda = (buyer: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("reservation"), resSalePda.toBuffer(), buyer.toBuffer()],
+        program.programId
+      )[0];
+
+    const purchaseAccounts = (buyer: web3.Keypair) => ({
+      sale: resSalePda,
+      userPurchase: web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), resSalePda.toBuffer(), buyer.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      buyer: buyer.publicKey,
+      tokenMint: resMint,
+      saleTokenVault: resVault,
+      buyerTokenAccount: getAssociatedTokenAddressSync(resMint, buyer.publicKey),
+      treasury: findTreasuryPda(resSalePda),
+      stakePosition: null,
+      stakeTokenAccount: null,
+      aggregateConfig: null,
+      aggregateLimit: null,
+      referrer: null,
+      referral: null,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+      rent: web3.SYSVAR_RENT_PUBKEY,
+    });
+
+    const reserve = (buyer: web3.Keypair, amount: BN) =>
+      program.methods
+        .reserveAllocation(amount, [])
+        .accounts({
+          sale: resSalePda,
+          reservation: findReservationPda(buyer.publicKey),
+          userPurchase: null,
+          buyer: buyer.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([buyer])
+        .rpc();
+
+    const purchaseReserved = (buyer: web3.Keypair) =>
+      program.methods
+        .purchaseReserved([])
+        .accounts({
+          purchase: purchaseAccounts(buyer),
+          reservation: findReservationPda(buyer.publicKey),
+        })
+        .signers([buyer])
+        .rpc();
+
+    const release = (buyer: web3.PublicKey, caller: web3.Keypair) =>
+      program.methods
+        .releaseReservation()
+        .accounts({
+          sale: resSalePda,
+          reservation: findReservationPda(buyer),
+          buyer: buyer,
+          caller: caller.publicKey,
+        })
+        .signers([caller])
+        .rpc();
+
+    const tokensReserved = async () =>
+      (await program.account.sale.fetch(resSalePda)).tokensReserved.toString();
+
+    before(async () => {
+      resAuthority = web3.Keypair.generate();
+      await Promise.all(
+        [resAuthority, holder, buyerB, buyerC, bystander].map(wallet =>
+          connection.requestAirdrop(wallet.publicKey, 5 * web3.LAMPORTS_PER_SOL)
+        )
+      );
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      resMint = await createMint(
+        connection,
+        resAuthority,
+        resAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+      [resSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), resAuthority.publicKey.toBuffer(), resMint.toBuffer()],
+        program.programId
+      );
+      resVault = await getAssociatedTokenAddress(resMint, resSalePda, true);
+
+      await program.methods
+        .initializeSale(
+          TOKEN_PRICE,
+          SUPPLY,
+          MIN_PURCHASE,
+          SUPPLY,
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: resSalePda,
+          authority: resAuthority.publicKey,
+          tokenMint: resMint,
+          treasury: findTreasuryPda(resSalePda),
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([resAuthority])
+        .rpc();
+
+      await mintTo(
+        connection,
+        resAuthority,
+        resMint,
+        resVault,
+        resAuthority.publicKey,
+        BigInt(SUPPLY.toString())
+      );
+    });
+
+    it("should reject reservations until a TTL is set", async () => {
+      try {
+        await reserve(holder, MIN_PURCHASE);
+        assert.fail("Should have failed with reservations disabled");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Reservations are not enabled for this sale");
+      }
+
+      await program.methods
+        .setReservationTtl(new BN(RESERVATION_TTL))
+        .accounts({ sale: resSalePda, authority: resAuthority.publicKey })
+        .signers([resAuthority])
+        .rpc();
+    });
+
+    it("should hold an allocation and convert it into a purchase", async () => {
+      const amount = tokens(300);
+      await reserve(holder, amount);
+      assert.equal(await tokensReserved(), amount.toString());
+
+      await purchaseReserved(holder);
+
+      const saleAccount = await program.account.sale.fetch(resSalePda);
+      assert.equal(saleAccount.tokensReserved.toString(), "0");
+      assert.equal(saleAccount.tokensSold.toString(), amount.toString());
+
+      const buyerTokens = await getAccount(
+        connection,
+        getAssociatedTokenAddressSync(resMint, holder.publicKey)
+      );
+      assert.equal(buyerTokens.amount.toString(), amount.toString());
+      assert.isNull(await connection.getAccountInfo(findReservationPda(holder.publicKey)));
+    });
+
+    it("should count concurrent holds against the supply", async () => {
+      // 300 sold + 400 + 300 held leaves nothing for anyone else
+      await Promise.all([reserve(buyerB, tokens(400)), reserve(buyerC, tokens(300))]);
+      assert.equal(await tokensReserved(), tokens(700).toString());
+
+      try {
+        await program.methods
+          .purchaseTokens(MIN_PURCHASE, false, [], null, NO_DEADLINE, null)
+          .accounts(purchaseAccounts(bystander))
+          .signers([bystander])
+          .rpc();
+        assert.fail("Should have failed with the supply held");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds maximum tokens for sale");
+      }
+
+      try {
+        await reserve(bystander, MIN_PURCHASE);
+        assert.fail("Should have failed with the supply held");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds maximum tokens for sale");
+      }
+    });
+
+    it("should not let others release an unexpired hold", async () => {
+      try {
+        await release(buyerB.publicKey, bystander);
+        assert.fail("Should have failed before expiry");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Reservation has not expired");
+      }
+    });
+
+    it("should expire holds and let anyone sweep them", async () => {
+      const reservation = await program.account.reservation.fetch(
+        findReservationPda(buyerC.publicKey)
+      );
+      await waitForClock(reservation.expiresAt.toNumber());
+
+      try {
+        await purchaseReserved(buyerC);
+        assert.fail("Should have failed after expiry");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Reservation has expired");
+      }
+
+      const balanceBefore = await connection.getBalance(buyerC.publicKey);
+      await release(buyerB.publicKey, bystander);
+      await release(buyerC.publicKey, bystander);
+      assert.equal(await tokensReserved(), "0");
+
+      // Rent goes back to the buyer, not the sweeper
+      assert.isAbove(await connection.getBalance(buyerC.publicKey), balanceBefore);
+
+      // The freed supply is purchasable again
+      await program.methods
+        .purchaseTokens(MIN_PURCHASE, false, [], null, NO_DEADLINE, null)
+        .accounts(purchaseAccounts(bystander))
+        .signers([bystander])
+        .rpc();
+    });
+  });
+
+  describe("Purchase With SOL", () => {
+    // 0.003 SOL per token, so SOL amounts rarely buy a whole number of base units
+    const SOL_SALE_PRICE = new BN(3_000_000);
+    const SUPPLY = tokens(1_000);
+
+    let solAuthority: web3.Keypair;
+    let solMint: web3.PublicKey;
+    let solSalePda: web3.PublicKey;
+    let solVault: web3.PublicKey;
+    const [spender, whale] = [web3.Keypair.generate(), web3.Keypair.generate()];
+
+    const tokensFor = (lamports: BN) => lamports.mul(ONE_TOKEN).div(SOL_SALE_PRICE);
+
+    const purchaseWithSol = (buyer: web3.Keypair, solAmount: BN, minTokensOut: BN) =>
+      program.methods
+        .purchaseWithSol(solAmount, minTokensOut, [])
+        .accounts({
+          sale: solSalePda,
+          userPurchase: web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("purchase"), solSalePda.toBuffer(), buyer.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          buyer: buyer.publicKey,
+          tokenMint: solMint,
+          saleTokenVault: solVault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(solMint, buyer.publicKey),
+          treasury: findTreasuryPda(solSalePda),
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateConfig: null,
+          aggregateLimit: null,
+          referrer: null,
+          referral: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer])
+        .rpc();
+
+    const tokenBalance = async (owner: web3.PublicKey) =>
+      (await getAccount(connection, getAssociatedTokenAddressSync(solMint, owner))).amount.toString();
+
+    before(async () => {
+      solAuthority = web3.Keypair.generate();
+      await Promise.all(
+        [solAuthority, spender, whale].map(wallet =>
+          connection.requestAirdrop(wallet.publicKey, 10 * web3.LAMPORTS_PER_SOL)
+        )
+      );
+      await new Promise(resolve => setTimeout(resolve, 1000));
+
+      solMint = await createMint(
+        connection,
+        solAuthority,
+        solAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+      [solSalePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), solAuthority.publicKey.toBuffer(), solMint.toBuffer()],
+        program.programId
+      );
+      solVault = await getAssociatedTokenAddress(solMint, solSalePda, true);
+
+      await program.methods
+        .initializeSale(
+          SOL_SALE_PRICE,
+          SUPPLY,
+          MIN_PURCHASE,
+          SUPPLY,
+          SALE_DURATION,
+          NO_START_DELAY,
+          STAKE_BONUS_BPS,
+          STAKE_LOCK_DURATION,
+          STAKE_MULTIPLIER_BPS,
+          NO_SOFT_CAP,
+          INSTANT_DELIVERY_BPS,
+          NO_CLIFF,
+          NO_VESTING,
+          NO_REFERRAL_BONUS,
+          MUTABLE_SALE,
+          NO_TX_CAP,
+          NO_COOLDOWN,
+          NO_LAUNCH_GUARD,
+          NO_LOT_SIZE,
+          DEFAULT_QUOTE_SYMBOL,
+          DEFAULT_QUOTE_DECIMALS,
+          DEFAULT_ROUNDING
+        )
+        .accounts({
+          sale: solSalePda,
+          authority: solAuthority.publicKey,
+          tokenMint: solMint,
+          treasury: findTreasuryPda(solSalePda),
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([solAuthority])
+        .rpc();
+
+      await mintTo(
+        connection,
+        solAuthority,
+        solMint,
+        solVault,
+        solAuthority.publicKey,
+        BigInt(SUPPLY.toString())
+      );
+    });
+
+    it("should reject a purchase below min_tokens_out", async () => {
+      const solAmount = new BN(web3.LAMPORTS_PER_SOL);
+      try {
+        await purchaseWithSol(spender, solAmount, tokensFor(solAmount).addn(1));
+        assert.fail("Should have failed on slippage");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Slippage exceeded");
+      }
+    });
+
+    it("should round tokens down and never undercharge", async () => {
+      const solAmount = new BN(web3.LAMPORTS_PER_SOL);
+      const expectedTokens = tokensFor(solAmount); // 333.333333333 tokens
+      const treasuryBefore = await connection.getBalance(findTreasuryPda(solSalePda));
+
+      await purchaseWithSol(spender, solAmount, expectedTokens);
+
+      assert.equal(await tokenBalance(spender.publicKey), expectedTokens.toString());
+      const charged = (await connection.getBalance(findTreasuryPda(solSalePda))) - treasuryBefore;
+      assert.equal(charged.toString(), costOf(expectedTokens, SOL_SALE_PRICE).toString());
+      assert.isAtMost(charged, solAmount.toNumber());
+    });
+
+    it("should fill the remaining supply and charge only for it", async () => {
+      const saleBefore = await program.account.sale.fetch(solSalePda);
+      const remaining = SUPPLY.sub(saleBefore.tokensSold);
+      const solAmount = new BN(5 * web3.LAMPORTS_PER_SOL); // buys far more than is left
+      const treasuryBefore = await connection.getBalance(findTreasuryPda(solSalePda));
+
+      await purchaseWithSol(whale, solAmount, remaining);
+
+      assert.equal(await tokenBalance(whale.publicKey), remaining.toString());
+      const charged = (await connection.getBalance(findTreasuryPda(solSalePda))) - treasuryBefore;
+      assert.equal(charged.toString(), costOf(remaining, SOL_SALE_PRICE).toString());
+
+      const saleAfter = await program.account.sale.fetch(solSalePda);
+      assert.equal(saleAfter.tokensSold.toString(), SUPPLY.toString());
+    });
+  });
+
+  describe("Lamport Floor", () => {
+    let floorSale: StandaloneSale;
+    let buyer: web3.Keypair;
+
+    const setFloor = (minLamportsPerToken: BN) =>
+      program.methods
+        .setLamportFloor(minLamportsPerToken)
+        .accounts({ sale: floorSale.salePda, authority: floorSale.authority.publicKey })
+        .signers([floorSale.authority])
+        .rpc();
+
+    const purchase = (autoStake: boolean) => {
+      const stakePda = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), floorSale.salePda.toBuffer(), buyer.publicKey.toBuffer()],
+        program.programId
+      )[0];
+      return program.methods
+        .purchaseTokens(MIN_PURCHASE, autoStake, [], null, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(floorSale, buyer.publicKey),
+          stakePosition: autoStake ? stakePda : null,
+          stakeTokenAccount: autoStake
+            ? getAssociatedTokenAddressSync(floorSale.mint, stakePda, true)
+            : null,
+        })
+        .signers([buyer])
+        .rpc();
+    };
+
+    before(async () => {
+      floorSale = await createFundedSale();
+      buyer = await fundedWallet();
+    });
+
+    it("should accept purchases exactly at the floor", async () => {
+      await setFloor(TOKEN_PRICE);
+      await purchase(false);
+
+      const userPurchase = await program.account.userPurchase.fetch(
+        solPurchaseAccounts(floorSale, buyer.publicKey).userPurchase
+      );
+      assert.equal(userPurchase.tokensPurchased.toString(), MIN_PURCHASE.toString());
+    });
+
+    it("should count staking bonuses against the floor", async () => {
+      // A 10% bonus delivers 110 tokens for the price of 100
+      try {
+        await purchase(true);
+        assert.fail("Should have failed below the floor");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("below the sale's lamport floor");
+      }
+    });
+
+    it("should reject purchases one lamport under the floor", async () => {
+      await setFloor(TOKEN_PRICE.addn(1));
+      try {
+        await purchase(false);
+        assert.fail("Should have failed below the floor");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("below the sale's lamport floor");
+        expect(error.logs.join("\n")).to.include("pay with purchase_tokens_with_spl instead");
+      }
+
+      // Disabling the floor lets the same purchase through
+      await setFloor(new BN(0));
+      await purchase(true);
+    });
+  });
+
+  describe("Aggregate Limits", () => {
+    const MAX_AGGREGATE = tokens(300);
+
+    let mintAuthority: web3.Keypair;
+    let sharedMint: web3.PublicKey;
+    let seedRound: StandaloneSale;
+    let publicRound: StandaloneSale;
+    let buyer: web3.Keypair;
+
+    const aggregateConfigPda = () =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("agg_config"), sharedMint.toBuffer()],
+        program.programId
+      )[0];
+    const aggregateLimitPda = (wallet: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("agg"), sharedMint.toBuffer(), wallet.toBuffer()],
+        program.programId
+      )[0];
+
+    const setAggregateLimit = (signer: web3.Keypair) =>
+      program.methods
+        .setAggregateLimit(MAX_AGGREGATE)
+        .accounts({
+          aggregateConfig: aggregateConfigPda(),
+          tokenMint: sharedMint,
+          mintAuthority: signer.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([signer])
+        .rpc();
+
+    const purchase = (sale: StandaloneSale, amount: BN, withAggregateAccounts = true) =>
+      program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(sale, buyer.publicKey),
+          aggregateConfig: withAggregateAccounts ? aggregateConfigPda() : null,
+          aggregateLimit: withAggregateAccounts ? aggregateLimitPda(buyer.publicKey) : null,
+          referrer: null,
+          referral: null,
+        })
+        .signers([buyer])
+        .rpc();
+
+    before(async () => {
+      mintAuthority = await fundedWallet();
+      sharedMint = await createMint(
+        connection,
+        mintAuthority,
+        mintAuthority.publicKey,
+        null,
+        TOKEN_DECIMALS
+      );
+      // Two rounds of the same mint, run by different sale authorities
+      seedRound = await createFundedSale({ mint: sharedMint, mintAuthority });
+      publicRound = await createFundedSale({ mint: sharedMint, mintAuthority });
+      buyer = await fundedWallet();
+
+      for (const round of [seedRound, publicRound]) {
+        await program.methods
+          .setEnforceAggregateLimit(true)
+          .accounts({ sale: round.salePda, authority: round.authority.publicKey })
+          .signers([round.authority])
+          .rpc();
+      }
+    });
+
+    it("should only let the mint authority configure the limit", async () => {
+      try {
+        await setAggregateLimit(seedRound.authority);
+        assert.fail("Should have failed for a sale authority");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Unauthorized access");
+      }
+
+      await setAggregateLimit(mintAuthority);
+      const config = await program.account.aggregateConfig.fetch(aggregateConfigPda());
+      assert.equal(config.maxAggregate.toString(), MAX_AGGREGATE.toString());
+    });
+
+    it("should require the aggregate accounts on enforcing sales", async () => {
+      try {
+        await purchase(seedRound, MIN_PURCHASE, false);
+        assert.fail("Should have failed without aggregate accounts");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Aggregate limit accounts are required");
+      }
+    });
+
+    it("should create the wallet's aggregate record on its first purchase", async () => {
+      await purchase(seedRound, tokens(200));
+
+      const record = await program.account.aggregateLimit.fetch(aggregateLimitPda(buyer.publicKey));
+      assert.equal(record.totalPurchased.toString(), tokens(200).toString());
+      assert.ok(record.mint.equals(sharedMint));
+    });
+
+    it("should trip the cap on a purchase in another round", async () => {
+      // Within publicRound's own per-wallet limit, but 200 + 200 > 300 across rounds
+      try {
+        await purchase(publicRound, tokens(200));
+        assert.fail("Should have failed on the aggregate cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds the wallet's aggregate limit");
+        expect(error.logs.join("\n")).to.include(
+          `remaining_allowance=${tokens(100)} `
+        );
+        expect(error.logs.join("\n")).to.include(`aggregate_remaining=${tokens(100)}`);
+      }
+
+      await purchase(publicRound, tokens(100));
+      const record = await program.account.aggregateLimit.fetch(aggregateLimitPda(buyer.publicKey));
+      assert.equal(record.totalPurchased.toString(), MAX_AGGREGATE.toString());
+    });
+
+    it("should give the refunded tokens back to the wallet's aggregate limit", async () => {
+      const refundRound = await createFundedSale({
+        mint: sharedMint,
+        mintAuthority,
+        softCap: tokens(900),
+      });
+      await program.methods
+        .setEnforceAggregateLimit(true)
+        .accounts({ sale: refundRound.salePda, authority: refundRound.authority.publicKey })
+        .signers([refundRound.authority])
+        .rpc();
+
+      const refunded = await fundedWallet();
+      await program.methods
+        .purchaseTokens(tokens(200), false, [], null, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(refundRound, refunded.publicKey),
+          aggregateConfig: aggregateConfigPda(),
+          aggregateLimit: aggregateLimitPda(refunded.publicKey),
+          referrer: null,
+          referral: null,
+        })
+        .signers([refunded])
+        .rpc();
+      await program.methods
+        .endSale()
+        .accounts({ sale: refundRound.salePda, authority: refundRound.authority.publicKey })
+        .signers([refundRound.authority])
+        .rpc();
+      await program.methods.finalizeSale().accounts({ sale: refundRound.salePda }).rpc();
+
+      const refund = (aggregateLimit: web3.PublicKey | null) =>
+        program.methods
+          .claimRefund()
+          .accounts({
+            sale: refundRound.salePda,
+            userPurchase: solPurchaseAccounts(refundRound, refunded.publicKey).userPurchase,
+            buyer: refunded.publicKey,
+            tokenMint: sharedMint,
+            saleTokenVault: refundRound.vault,
+            buyerTokenAccount: getAssociatedTokenAddressSync(sharedMint, refunded.publicKey),
+            treasury: findTreasuryPda(refundRound.salePda),
+            stakePosition: null,
+            stakeTokenAccount: null,
+            aggregateLimit,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([refunded])
+          .rpc();
+
+      try {
+        await refund(null);
+        assert.fail("Should have failed without the aggregate record");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Aggregate limit accounts are required");
+      }
+
+      await refund(aggregateLimitPda(refunded.publicKey));
+      const record = await program.account.aggregateLimit.fetch(aggregateLimitPda(refunded.publicKey));
+      assert.equal(record.totalPurchased.toString(), "0");
+
+      // The full cap is available again in another round
+      await program.methods
+        .purchaseTokens(MAX_AGGREGATE, false, [], null, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(publicRound, refunded.publicKey),
+          aggregateConfig: aggregateConfigPda(),
+          aggregateLimit: aggregateLimitPda(refunded.publicKey),
+          referrer: null,
+          referral: null,
+        })
+        .signers([refunded])
+        .rpc();
+    });
+  });
+
+  describe("Tiered Pricing", () => {
+    // 0.001 SOL for the first 300 tokens, 0.002 SOL up to 700, then 0.003 SOL
+    const TIERS = [
+      { price: new BN(1_000_000), tokenCapCumulative: tokens(300) },
+      { price: new BN(2_000_000), tokenCapCumulative: tokens(700) },
+      { price: new BN(3_000_000), tokenCapCumulative: tokens(1_000) },
+    ];
+
+    let tieredSale: StandaloneSale;
+    let buyer: web3.Keypair;
+
+    const setTiers = (sale: StandaloneSale, tiers: typeof TIERS) =>
+      program.methods
+        .setTiers(tiers)
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    // Lamports the treasury receives for a purchase
+    const purchaseCost = async (sale: StandaloneSale, amount: BN) => {
+      const treasury = findTreasuryPda(sale.salePda);
+      const before = await connection.getBalance(treasury);
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+      return (await connection.getBalance(treasury)) - before;
+    };
+
+    before(async () => {
+      tieredSale = await createFundedSale({ startDelay: new BN(4) });
+      buyer = await fundedWallet();
+    });
+
+    it("should reject caps that do not increase", async () => {
+      try {
+        await setTiers(tieredSale, [TIERS[1], TIERS[0], TIERS[2]]);
+        assert.fail("Should have failed with decreasing caps");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Price tier caps must strictly increase");
+      }
+    });
+
+    it("should reject a last cap other than max tokens", async () => {
+      try {
+        await setTiers(tieredSale, TIERS.slice(0, 2));
+        assert.fail("Should have failed with a short last cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("end at max tokens");
+      }
+    });
+
+    it("should store tiers before the sale starts", async () => {
+      await setTiers(tieredSale, TIERS);
+
+      const saleAccount = await program.account.sale.fetch(tieredSale.salePda);
+      assert.equal(saleAccount.tierCount, TIERS.length);
+      assert.equal(saleAccount.tiers[1].price.toString(), TIERS[1].price.toString());
+    });
+
+    it("should price a purchase inside the first tier", async () => {
+      await waitForClock((await program.account.sale.fetch(tieredSale.salePda)).startTime.toNumber());
+
+      const cost = await purchaseCost(tieredSale, tokens(200));
+      assert.equal(cost, 200 * 1_000_000);
+    });
+
+    it("should split a purchase that straddles a tier boundary", async () => {
+      // 100 tokens left at 0.001 SOL, the other 100 at 0.002 SOL
+      const cost = await purchaseCost(tieredSale, tokens(200));
+      assert.equal(cost, 100 * 1_000_000 + 100 * 2_000_000);
+    });
+
+    it("should not change tiers after the sale starts", async () => {
+      try {
+        await setTiers(tieredSale, TIERS);
+        assert.fail("Should have failed after start");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale has already started");
+      }
+    });
+
+    it("should match flat pricing with a single tier", async () => {
+      const singleTierSale = await createFundedSale({ startDelay: new BN(4) });
+      const flatSale = await createFundedSale();
+      await setTiers(singleTierSale, [{ price: TOKEN_PRICE, tokenCapCumulative: tokens(1_000) }]);
+      await waitForClock(
+        (await program.account.sale.fetch(singleTierSale.salePda)).startTime.toNumber()
+      );
+
+      const amount = tokens(123).addn(456_789); // not a whole number of tokens
+      assert.equal(
+        await purchaseCost(singleTierSale, amount),
+        await purchaseCost(flatSale, amount)
+      );
+    });
+  });
+
+  describe("CPI Return Data", () => {
+    it("should return the purchase receipt to CPI callers", async () => {
+      const sale = await createFundedSale();
+      const buyer = await fundedWallet();
+      const accounts = solPurchaseAccounts(sale, buyer.publicKey);
+
+      const signature = await integrator.methods
+        .buy(MIN_PURCHASE)
+        .accounts({
+          sale: accounts.sale,
+          userPurchase: accounts.userPurchase,
+          buyer: buyer.publicKey,
+          tokenMint: accounts.tokenMint,
+          saleTokenVault: accounts.saleTokenVault,
+          buyerTokenAccount: accounts.buyerTokenAccount,
+          treasury: accounts.treasury,
+          icoProgram: program.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .signers([buyer])
+        .rpc({ commitment: "confirmed" });
+
+      const tx = await connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const logs = tx.meta.logMessages;
+      const purchased = [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(logs),
+      ].find(event => event.name === "tokensPurchased").data;
+      const relayed = [
+        ...new anchor.EventParser(integrator.programId, integrator.coder).parseLogs(logs),
+      ].find(event => event.name === "receiptRelayed").data;
+
+      // The integrator only sees what purchase_tokens returned
+      assert.equal(relayed.tokensDelivered.toString(), purchased.tokenAmount.toString());
+      assert.equal(relayed.solCost.toString(), purchased.solCost.toString());
+      assert.equal(relayed.priceUsed.toString(), purchased.averagePrice.toString());
+      assert.equal(relayed.userTotal.toString(), MIN_PURCHASE.toString());
+    });
+  });
+
+  describe("Treasury Withdrawal & Authority Handover", () => {
+    let managedSale: StandaloneSale;
+    let treasury: web3.PublicKey;
+    const destination = web3.Keypair.generate();
+    const newAuthority = web3.Keypair.generate();
+
+    const buy = async (sale: StandaloneSale, amount: BN) => {
+      const buyer = await fundedWallet();
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+    };
+
+    const endSale = (sale: StandaloneSale) =>
+      program.methods
+        .endSale()
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    const withdrawTreasury = (sale: StandaloneSale, signer: web3.Keypair, amount: BN) =>
+      program.methods
+        .withdrawTreasury(amount)
+        .accounts({
+          sale: sale.salePda,
+          authority: signer.publicKey,
+          treasury: findTreasuryPda(sale.salePda),
+          destination: destination.publicKey,
+        })
+        .signers([signer])
+        .rpc();
+
+    // Lamports above the treasury's rent-exempt minimum
+    const withdrawable = async () => {
+      const info = await connection.getAccountInfo(treasury);
+      const rentExempt = await connection.getMinimumBalanceForRentExemption(info.data.length);
+      return new BN(info.lamports - rentExempt);
+    };
+
+    before(async () => {
+      managedSale = await createFundedSale();
+      treasury = findTreasuryPda(managedSale.salePda);
+      await buy(managedSale, MIN_PURCHASE);
+    });
+
+    it("should not withdraw while the sale is active", async () => {
+      try {
+        await withdrawTreasury(managedSale, managedSale.authority, new BN(1));
+        assert.fail("Should have failed while sale is active");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale is still active");
+      }
+    });
+
+    it("should not withdraw the treasury's rent reserve", async () => {
+      await endSale(managedSale);
+      try {
+        await withdrawTreasury(managedSale, managedSale.authority, (await withdrawable()).addn(1));
+        assert.fail("Should have failed above the withdrawable balance");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("exceeds the treasury balance");
+      }
+    });
+
+    it("should withdraw raised SOL to a destination", async () => {
+      const amount = await withdrawable();
+      assert.equal(amount.toString(), costOf(MIN_PURCHASE).toString());
+
+      await withdrawTreasury(managedSale, managedSale.authority, amount);
+
+      assert.equal(await connection.getBalance(destination.publicKey), amount.toNumber());
+      assert.equal((await withdrawable()).toString(), "0");
+    });
+
+    it("should keep soft-cap proceeds until the sale succeeds", async () => {
+      const cappedSale = await createFundedSale({ softCap: tokens(500) });
+      await buy(cappedSale, MIN_PURCHASE);
+      await endSale(cappedSale);
+
+      try {
+        await withdrawTreasury(cappedSale, cappedSale.authority, new BN(1));
+        assert.fail("Should have failed before finalization");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale must be finalized as successful first");
+      }
+
+      await program.methods.finalizeSale().accounts({ sale: cappedSale.salePda }).rpc();
+      try {
+        await withdrawTreasury(cappedSale, cappedSale.authority, new BN(1));
+        assert.fail("Should have failed on a failed sale");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale must be finalized as successful first");
+      }
+    });
+
+    it("should only let the pending authority accept a handover", async () => {
+      await program.methods
+        .proposeAuthority(newAuthority.publicKey)
+        .accounts({ sale: managedSale.salePda, authority: managedSale.authority.publicKey })
+        .signers([managedSale.authority])
+        .rpc();
+
+      const impostor = web3.Keypair.generate();
+      try {
+        await program.methods
+          .acceptAuthority()
+          .accounts({ sale: managedSale.salePda, newAuthority: impostor.publicKey })
+          .signers([impostor])
+          .rpc();
+        assert.fail("Should have failed for a key that was not proposed");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Signer is not the pending authority");
+      }
+
+      await program.methods
+        .acceptAuthority()
+        .accounts({ sale: managedSale.salePda, newAuthority: newAuthority.publicKey })
+        .signers([newAuthority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(managedSale.salePda);
+      assert.ok(saleAccount.authority.equals(newAuthority.publicKey));
+      assert.ok(saleAccount.seedAuthority.equals(managedSale.authority.publicKey));
+      assert.isNull(saleAccount.pendingAuthority);
+    });
+
+    it("should hand authority-only instructions to the new authority", async () => {
+      try {
+        await withdrawTreasury(managedSale, managedSale.authority, new BN(0));
+        assert.fail("Should have failed for the previous authority");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Unauthorized access");
+      }
+
+      // The sale PDA is unchanged, so the new authority signs against the same address
+      await withdrawTreasury(managedSale, newAuthority, new BN(0));
+    });
+  });
+
+  describe("Referrals", () => {
+    const REFERRAL_BPS = 500; // 5% of each referred purchase
+
+    let referralSale: StandaloneSale;
+    let referrer: web3.Keypair;
+
+    const findReferralPda = (sale: StandaloneSale, wallet: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("referral"), sale.salePda.toBuffer(), wallet.toBuffer()],
+        program.programId
+      )[0];
+
+    const referredPurchase = (buyer: web3.Keypair, amount: BN, referrerKey: web3.PublicKey) =>
+      program.methods
+        .purchaseTokens(amount, false, [], referrerKey, NO_DEADLINE, null)
+        .accounts({
+          ...solPurchaseAccounts(referralSale, buyer.publicKey),
+          referrer: referrerKey,
+          referral: findReferralPda(referralSale, referrerKey),
+        })
+        .signers([buyer])
+        .rpc();
+
+    const claimReferralBonus = () =>
+      program.methods
+        .claimReferralBonus()
+        .accounts({
+          sale: referralSale.salePda,
+          referral: findReferralPda(referralSale, referrer.publicKey),
+          referrer: referrer.publicKey,
+          tokenMint: referralSale.mint,
+          saleTokenVault: referralSale.vault,
+          referrerTokenAccount: getAssociatedTokenAddressSync(
+            referralSale.mint,
+            referrer.publicKey
+          ),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([referrer])
+        .rpc();
+
+    before(async () => {
+      referralSale = await createFundedSale({ referralBps: REFERRAL_BPS });
+      referrer = await fundedWallet();
+    });
+
+    it("should cap the referral bonus at initialization", async () => {
+      try {
+        await createFundedSale({ referralBps: 1_001 });
+        assert.fail("Should have failed above MAX_REFERRAL_BPS");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Referral bonus cannot exceed");
+      }
+    });
+
+    it("should reject self-referral", async () => {
+      try {
+        await referredPurchase(referrer, MIN_PURCHASE, referrer.publicKey);
+        assert.fail("Should have failed for a self-referral");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Buyers cannot refer themselves");
+      }
+    });
+
+    it("should reject referral accounts without a referrer argument", async () => {
+      const buyer = await fundedWallet();
+      try {
+        await program.methods
+          .purchaseTokens(MIN_PURCHASE, false, [], null, NO_DEADLINE, null)
+          .accounts({
+            ...solPurchaseAccounts(referralSale, buyer.publicKey),
+            referrer: referrer.publicKey,
+            referral: findReferralPda(referralSale, referrer.publicKey),
+          })
+          .signers([buyer])
+          .rpc();
+        assert.fail("Should have failed with unused referral accounts");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Referral accounts are required and must match");
+      }
+      assert.isNull(
+        await connection.getAccountInfo(findReferralPda(referralSale, referrer.publicKey))
+      );
+    });
+
+    it("should credit the referrer on referred purchases", async () => {
+      const buyer = await fundedWallet();
+      await referredPurchase(buyer, tokens(200), referrer.publicKey);
+      await referredPurchase(buyer, tokens(100), referrer.publicKey);
+
+      const referral = await program.account.referralAccount.fetch(
+        findReferralPda(referralSale, referrer.publicKey)
+      );
+      assert.ok(referral.referrer.equals(referrer.publicKey));
+      assert.equal(referral.bonusTokensEarned.toString(), tokens(15).toString());
+      assert.equal(referral.referredVolume.toString(), tokens(300).toString());
+
+      const saleAccount = await program.account.sale.fetch(referralSale.salePda);
+      assert.equal(saleAccount.referralBonusAllocated.toString(), tokens(15).toString());
+    });
+
+    it("should count referral bonuses against max_tokens", async () => {
+      // 300 sold + 15 bonus leaves 685; 660 more would add a 33 token bonus
+      const buyer = await fundedWallet();
+      try {
+        await referredPurchase(buyer, tokens(660), referrer.publicKey);
+        assert.fail("Should have failed with the bonus over the supply");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds maximum tokens for sale");
+      }
+
+      await referredPurchase(buyer, tokens(600), referrer.publicKey);
+      const saleAccount = await program.account.sale.fetch(referralSale.salePda);
+      assert.equal(saleAccount.referralBonusAllocated.toString(), tokens(45).toString());
+    });
+
+    it("should not pay the bonus before the sale ends", async () => {
+      try {
+        await claimReferralBonus();
+        assert.fail("Should have failed while sale is active");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale is still active");
+      }
+    });
+
+    it("should pay the bonus once the sale ends", async () => {
+      await program.methods
+        .endSale()
+        .accounts({ sale: referralSale.salePda, authority: referralSale.authority.publicKey })
+        .signers([referralSale.authority])
+        .rpc();
+
+      await claimReferralBonus();
+
+      const balance = await connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(referralSale.mint, referrer.publicKey)
+      );
+      assert.equal(balance.value.amount, tokens(45).toString());
+
+      try {
+        await claimReferralBonus();
+        assert.fail("Should have failed with nothing left to claim");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("No newly vested tokens to claim");
+      }
+    });
+  });
+
+  describe("Immutable Sales", () => {
+    let frozenSale: StandaloneSale;
+
+    const authorityAccounts = () => ({
+      sale: frozenSale.salePda,
+      authority: frozenSale.authority.publicKey,
+    });
+
+    const adminInstructions: [string, () => Promise<string>][] = [
+      [
+        "update_sale_params",
+        () =>
+          program.methods
+            .updateSaleParams(TOKEN_PRICE.muln(2), null, null, null, null, null, null)
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_payment_mint",
+        () =>
+          program.methods
+            .setPaymentMint(new BN(1_000_000))
+            .accounts({
+              ...authorityAccounts(),
+              paymentMint: frozenSale.mint,
+              paymentTreasury: frozenSale.vault,
+            })
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_allowlist",
+        () =>
+          program.methods
+            .setAllowlist(Array(32).fill(1), new BN(0), new BN(0))
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_reservation_ttl",
+        () =>
+          program.methods
+            .setReservationTtl(new BN(60))
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_tiers",
+        () =>
+          program.methods
+            .setTiers([{ price: TOKEN_PRICE, tokenCapCumulative: tokens(1_000) }])
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_lamport_floor",
+        () =>
+          program.methods
+            .setLamportFloor(new BN(1))
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_enforce_aggregate_limit",
+        () =>
+          program.methods
+            .setEnforceAggregateLimit(true)
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "set_snapshot_root",
+        () =>
+          program.methods
+            .setSnapshotRoot(Array(32).fill(1), [{ minBalance: tokens(1), discountBps: 1_000 }])
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+      [
+        "propose_authority",
+        () =>
+          program.methods
+            .proposeAuthority(web3.Keypair.generate().publicKey)
+            .accounts(authorityAccounts())
+            .signers([frozenSale.authority])
+            .rpc(),
+      ],
+    ];
+
+    before(async () => {
+      // Not yet started, so every pre-launch setter would otherwise succeed
+      frozenSale = await createFundedSale({ immutable: true, startDelay: new BN(3600) });
+    });
+
+    it("should record the flag and surface it in the info view", async () => {
+      const saleAccount = await program.account.sale.fetch(frozenSale.salePda);
+      assert.isTrue(saleAccount.immutable);
+
+      const info = await program.methods
+        .getUserInfo()
+        .accounts({
+          sale: frozenSale.salePda,
+          user: web3.Keypair.generate().publicKey,
+          userPurchase: null,
+        })
+        .view();
+      assert.isTrue(info.saleImmutable);
+    });
+
+    for (const [name, call] of adminInstructions) {
+      it(`should reject ${name}`, async () => {
+        try {
+          await call();
+          assert.fail(`${name} should have failed on an immutable sale`);
+        } catch (error) {
+          expect(error.error.errorMessage).to.include("Sale parameters are immutable");
+        }
+      });
+    }
+
+    it("should still allow pausing and ending the sale", async () => {
+      for (let i = 0; i < 2; i++) {
+        await program.methods
+          .togglePause()
+          .accounts(authorityAccounts())
+          .signers([frozenSale.authority])
+          .rpc();
+      }
+      await program.methods
+        .endSale()
+        .accounts(authorityAccounts())
+        .signers([frozenSale.authority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(frozenSale.salePda);
+      assert.isFalse(saleAccount.isPaused);
+      assert.isFalse(saleAccount.isActive);
+      assert.isTrue(saleAccount.immutable);
+    });
+  });
+
+  describe("Anti-Bot Controls", () => {
+    const TX_CAP = tokens(300);
+    const COOLDOWN = new BN(3600);
+
+    const purchase = (sale: StandaloneSale, buyer: web3.Keypair, amount: BN) =>
+      program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+
+    const findPurchasePda = (sale: StandaloneSale, buyer: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("purchase"), sale.salePda.toBuffer(), buyer.toBuffer()],
+        program.programId
+      )[0];
+
+    let guardedSale: StandaloneSale;
+
+    before(async () => {
+      guardedSale = await createFundedSale({ maxPurchasePerTx: TX_CAP, cooldownSeconds: COOLDOWN });
+    });
+
+    it("should not block a wallet's first purchase", async () => {
+      const buyer = await fundedWallet();
+      await purchase(guardedSale, buyer, MIN_PURCHASE);
+
+      const record = await program.account.userPurchase.fetch(
+        findPurchasePda(guardedSale, buyer.publicKey)
+      );
+      assert.equal(record.purchaseCount, 1);
+      assert.isAbove(record.lastPurchaseTs.toNumber(), 0);
+
+      try {
+        await purchase(guardedSale, buyer, MIN_PURCHASE);
+        assert.fail("Should have failed during the cooldown");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Purchase cooldown is still active");
+        expect(error.logs.join("\n")).to.include("cooldown_remaining_seconds=");
+      }
+    });
+
+    it("should cap a single transaction below the lifetime limit", async () => {
+      const buyer = await fundedWallet();
+      try {
+        await purchase(guardedSale, buyer, TX_CAP.addn(1));
+        assert.fail("Should have failed above max_purchase_per_tx");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds the per-transaction purchase limit");
+        expect(error.logs.join("\n")).to.include(`max_purchase_per_tx=${TX_CAP}`);
+        expect(error.logs.join("\n")).to.include("remaining_allowance=");
+      }
+
+      await purchase(guardedSale, buyer, TX_CAP);
+    });
+
+    it("should halve the per-transaction cap during the launch guard", async () => {
+      const launchSale = await createFundedSale({
+        maxPurchasePerTx: TX_CAP,
+        launchGuardSeconds: new BN(3600),
+      });
+      const buyer = await fundedWallet();
+      try {
+        await purchase(launchSale, buyer, TX_CAP);
+        assert.fail("Should have failed above the halved cap");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds the per-transaction purchase limit");
+      }
+
+      // No cooldown on this sale, so the same wallet can buy again right away
+      await purchase(launchSale, buyer, TX_CAP.divn(2));
+      await purchase(launchSale, buyer, TX_CAP.divn(2));
+    });
+
+    it("should adjust the controls before the sale starts", async () => {
+      const pendingSale = await createFundedSale({ startDelay: new BN(3600) });
+      await program.methods
+        .updateSaleParams(null, null, null, null, TX_CAP, COOLDOWN, new BN(600))
+        .accounts({ sale: pendingSale.salePda, authority: pendingSale.authority.publicKey })
+        .signers([pendingSale.authority])
+        .rpc();
+
+      const saleAccount = await program.account.sale.fetch(pendingSale.salePda);
+      assert.equal(saleAccount.maxPurchasePerTx.toString(), TX_CAP.toString());
+      assert.equal(saleAccount.purchaseCooldownSeconds.toString(), COOLDOWN.toString());
+      assert.equal(saleAccount.launchGuardSeconds.toString(), "600");
+    });
+  });
+
+  describe("Purchase Deadline", () => {
+    let deadlineSale: StandaloneSale;
+    let buyer: web3.Keypair;
+
+    const purchaseBefore = (validUntil: BN) =>
+      program.methods
+        .purchaseTokens(MIN_PURCHASE, false, [], null, validUntil, null)
+        .accounts(solPurchaseAccounts(deadlineSale, buyer.publicKey))
+        .signers([buyer])
+        .rpc({ commitment: "confirmed" });
+
+    const clusterTime = async () => connection.getBlockTime(await connection.getSlot());
+
+    before(async () => {
+      deadlineSale = await createFundedSale();
+      buyer = await fundedWallet();
+    });
+
+    it("should reject a purchase one second past its deadline", async () => {
+      // The clock is at least `now` by the time the transaction lands
+      const now = await clusterTime();
+      const balanceBefore = await connection.getBalance(buyer.publicKey);
+      try {
+        await purchaseBefore(new BN(now - 1));
+        assert.fail("Should have failed past the deadline");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Purchase deadline has passed");
+      }
+
+      // Nothing was charged
+      assert.equal(await connection.getBalance(buyer.publicKey), balanceBefore);
+    });
+
+    it("should accept a purchase before its deadline and record it", async () => {
+      const validUntil = new BN((await clusterTime()) + 60);
+      const signature = await purchaseBefore(validUntil);
+
+      const tx = await connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const purchased = [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(
+          tx.meta.logMessages
+        ),
+      ].find(event => event.name === "tokensPurchased").data;
+      assert.equal(purchased.validUntil.toString(), validUntil.toString());
+    });
+
+    it("should not enforce a zero deadline", async () => {
+      await purchaseBefore(NO_DEADLINE);
+    });
+  });
+
+  describe("Proceeds Reconciliation", () => {
+    const reconcile = (sale: StandaloneSale) =>
+      program.methods
+        .reconcile()
+        .accounts({ sale: sale.salePda, treasury: findTreasuryPda(sale.salePda) })
+        .view();
+
+    const assertBalanced = async (sale: StandaloneSale) => {
+      const books = await reconcile(sale);
+      assert.equal(books.drift.toString(), "0");
+      assert.equal(
+        books.treasuryBalance.add(books.refundsPaid).add(books.treasuryWithdrawn).toString(),
+        books.totalRaised.toString()
+      );
+      return books;
+    };
+
+    const buy = async (sale: StandaloneSale, amount: BN) => {
+      const buyer = await fundedWallet();
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+      return buyer;
+    };
+
+    const endSale = (sale: StandaloneSale) =>
+      program.methods
+        .endSale()
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+
+    it("should balance across purchases and treasury withdrawals", async () => {
+      const sale = await createFundedSale();
+      await buy(sale, MIN_PURCHASE);
+      await buy(sale, tokens(250));
+      await assertBalanced(sale);
+
+      await endSale(sale);
+      const destination = web3.Keypair.generate().publicKey;
+      await program.methods
+        .withdrawTreasury(costOf(MIN_PURCHASE))
+        .accounts({
+          sale: sale.salePda,
+          authority: sale.authority.publicKey,
+          treasury: findTreasuryPda(sale.salePda),
+          destination,
+        })
+        .signers([sale.authority])
+        .rpc();
+
+      const books = await assertBalanced(sale);
+      assert.equal(books.treasuryWithdrawn.toString(), costOf(MIN_PURCHASE).toString());
+      assert.equal(books.treasuryBalance.toString(), costOf(tokens(250)).toString());
+    });
+
+    it("should balance after refunds of a failed sale", async () => {
+      const sale = await createFundedSale({ softCap: tokens(900) });
+      const refunded = await buy(sale, tokens(200));
+      await buy(sale, MIN_PURCHASE);
+      await endSale(sale);
+      await program.methods.finalizeSale().accounts({ sale: sale.salePda }).rpc();
+
+      await program.methods
+        .claimRefund()
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: solPurchaseAccounts(sale, refunded.publicKey).userPurchase,
+          buyer: refunded.publicKey,
+          tokenMint: sale.mint,
+          saleTokenVault: sale.vault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(sale.mint, refunded.publicKey),
+          treasury: findTreasuryPda(sale.salePda),
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateLimit: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([refunded])
+        .rpc();
+
+      const books = await assertBalanced(sale);
+      assert.equal(books.refundsPaid.toString(), costOf(tokens(200)).toString());
+      assert.equal(books.treasuryBalance.toString(), costOf(MIN_PURCHASE).toString());
+    });
+
+    it("should report lamports sent to the treasury outside a purchase as drift", async () => {
+      const sale = await createFundedSale();
+      await buy(sale, MIN_PURCHASE);
+
+      const stray = 12_345;
+      await provider.sendAndConfirm(
+        new web3.Transaction().add(
+          web3.SystemProgram.transfer({
+            fromPubkey: provider.wallet.publicKey,
+            toPubkey: findTreasuryPda(sale.salePda),
+            lamports: stray,
+          })
+        )
+      );
+
+      const books = await reconcile(sale);
+      assert.equal(books.drift.toString(), stray.toString());
+    });
+  });
+
+  describe("Sponsored Purchases", () => {
+    let sponsoredSale: StandaloneSale;
+    let sponsor: web3.Keypair;
+
+    const sponsoredAccounts = (buyer: web3.PublicKey, payer = sponsor.publicKey) => ({
+      ...solPurchaseAccounts(sponsoredSale, buyer),
+      sponsor: payer,
+    });
+
+    const purchaseSponsored = (buyer: web3.Keypair, amount: BN, signers = [buyer, sponsor]) =>
+      program.methods
+        .purchaseSponsored(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(sponsoredAccounts(buyer.publicKey))
+        .signers(signers)
+        .rpc();
+
+    before(async () => {
+      sponsoredSale = await createFundedSale({ maxPurchase: tokens(200) });
+      sponsor = await fundedWallet();
+    });
+
+    it("should require the sponsor's signature", async () => {
+      const buyer = web3.Keypair.generate();
+      try {
+        await purchaseSponsored(buyer, MIN_PURCHASE, [buyer]);
+        assert.fail("Should have failed without the sponsor signing");
+      } catch (error) {
+        expect(String(error)).to.match(/signature/i);
+      }
+    });
+
+    it("should charge the sponsor and credit the buyer", async () => {
+      // The buyer holds no SOL at all; the sponsor covers cost and rent
+      const buyer = web3.Keypair.generate();
+      const sponsorBefore = await connection.getBalance(sponsor.publicKey);
+
+      await purchaseSponsored(buyer, tokens(200));
+
+      const record = await program.account.userPurchase.fetch(
+        sponsoredAccounts(buyer.publicKey).userPurchase
+      );
+      assert.ok(record.user.equals(buyer.publicKey));
+      assert.equal(record.solContributed.toString(), costOf(tokens(200)).toString());
+
+      const balance = await connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(sponsoredSale.mint, buyer.publicKey)
+      );
+      assert.equal(balance.value.amount, tokens(200).toString());
+      assert.isAtLeast(
+        sponsorBefore - (await connection.getBalance(sponsor.publicKey)),
+        costOf(tokens(200)).toNumber()
+      );
+      assert.equal(await connection.getBalance(buyer.publicKey), 0);
+
+      // The buyer is at their per-wallet limit, whoever pays
+      try {
+        await purchaseSponsored(buyer, MIN_PURCHASE);
+        assert.fail("Should have failed on the buyer's limit");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Exceeds user purchase limit");
+      }
+    });
+
+    it("should not hold the sponsor to a buyer's limit", async () => {
+      await purchaseSponsored(web3.Keypair.generate(), tokens(200));
+    });
+
+    it("should reject a buyer sponsoring themselves", async () => {
+      const buyer = await fundedWallet();
+      try {
+        await program.methods
+          .purchaseSponsored(MIN_PURCHASE, false, [], null, NO_DEADLINE, null)
+          .accounts(sponsoredAccounts(buyer.publicKey, buyer.publicKey))
+          .signers([buyer])
+          .rpc();
+        assert.fail("Should have failed with the buyer as sponsor");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("must be paid by a wallet other than the buyer");
+      }
+    });
+
+    it("should honor the purchase deadline like any other purchase", async () => {
+      const buyer = web3.Keypair.generate();
+      try {
+        await program.methods
+          .purchaseSponsored(MIN_PURCHASE, false, [], null, new BN(1), null)
+          .accounts(sponsoredAccounts(buyer.publicKey))
+          .signers([buyer, sponsor])
+          .rpc();
+        assert.fail("Should have failed past the deadline");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Purchase deadline has passed");
+      }
+    });
+  });
+
+  describe("Snapshot Discounts", () => {
+    // 10% off from 1,000 snapshot tokens, 20% off from 5,000
+    const DISCOUNT_TIERS = [
+      { minBalance: tokens(1_000), discountBps: 1_000 },
+      { minBalance: tokens(5_000), discountBps: 2_000 },
+    ];
+
+    let discountSale: StandaloneSale;
+    let otherSale: StandaloneSale;
+    let whale: web3.Keypair;
+    let holder: web3.Keypair;
+
+    type Holding = { wallet: web3.PublicKey; balance: BN };
+
+    // Leaves bind the sale: keccak(sale || wallet || balance as u64 LE)
+    const buildSnapshot = (sale: web3.PublicKey, holdings: Holding[]) => {
+      const tree = merkleTree(
+        holdings.map(({ wallet, balance }) =>
+          Buffer.from(
+            keccak_256(
+              Buffer.concat([sale.toBuffer(), wallet.toBuffer(), balance.toArrayLike(Buffer, "le", 8)])
+            )
+          )
+        )
+      );
+      return {
+        root: tree.root,
+        proofFor: (wallet: web3.PublicKey) => {
+          const index = holdings.findIndex(h => h.wallet.equals(wallet));
+          return { balance: holdings[index].balance, proof: tree.proof(index) };
+        },
+      };
+    };
+
+    const setSnapshotRoot = (root: number[]) =>
+      program.methods
+        .setSnapshotRoot(root, DISCOUNT_TIERS)
+        .accounts({ sale: discountSale.salePda, authority: discountSale.authority.publicKey })
+        .signers([discountSale.authority])
+        .rpc();
+
+    const purchaseWithSnapshot = (buyer: web3.Keypair, snapshot: { balance: BN; proof: number[][] }) =>
+      program.methods
+        .purchaseTokens(MIN_PURCHASE, false, [], null, NO_DEADLINE, snapshot)
+        .accounts(solPurchaseAccounts(discountSale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+
+    const solContributed = async (buyer: web3.PublicKey) =>
+      (
+        await program.account.userPurchase.fetch(
+          solPurchaseAccounts(discountSale, buyer).userPurchase
+        )
+      ).solContributed;
+
+    let holdings: Holding[];
+
+    before(async () => {
+      discountSale = await createFundedSale();
+      otherSale = await createFundedSale();
+      whale = await fundedWallet();
+      holder = await fundedWallet();
+      holdings = [
+        { wallet: whale.publicKey, balance: tokens(8_000) },
+        { wallet: holder.publicKey, balance: tokens(1_500) },
+        { wallet: web3.Keypair.generate().publicKey, balance: tokens(200) },
+      ];
+      await setSnapshotRoot(buildSnapshot(discountSale.salePda, holdings).root);
+    });
+
+    it("should discount by the tier the proven balance reaches", async () => {
+      const snapshot = buildSnapshot(discountSale.salePda, holdings);
+      await purchaseWithSnapshot(whale, snapshot.proofFor(whale.publicKey));
+      await purchaseWithSnapshot(holder, snapshot.proofFor(holder.publicKey));
+
+      // costOf(MIN_PURCHASE) is a whole number of lamports, so both discounts are exact
+      assert.equal(
+        (await solContributed(whale.publicKey)).toString(),
+        costOf(MIN_PURCHASE).muln(8).divn(10).toString()
+      );
+      assert.equal(
+        (await solContributed(holder.publicKey)).toString(),
+        costOf(MIN_PURCHASE).muln(9).divn(10).toString()
+      );
+    });
+
+    it("should reject a balance the snapshot does not contain", async () => {
+      const { proof } = buildSnapshot(discountSale.salePda, holdings).proofFor(holder.publicKey);
+      try {
+        await purchaseWithSnapshot(holder, { balance: tokens(8_000), proof });
+        assert.fail("Should have failed for an inflated balance");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Snapshot proof does not match");
+      }
+    });
+
+    it("should reject a proof built for another sale", async () => {
+      // Same holders and balances, but the leaves commit to the other sale's key
+      const foreign = buildSnapshot(otherSale.salePda, holdings);
+      await program.methods
+        .setSnapshotRoot(foreign.root, DISCOUNT_TIERS)
+        .accounts({ sale: otherSale.salePda, authority: otherSale.authority.publicKey })
+        .signers([otherSale.authority])
+        .rpc();
+
+      try {
+        await purchaseWithSnapshot(whale, foreign.proofFor(whale.publicKey));
+        assert.fail("Should have failed for another sale's proof");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Snapshot proof does not match");
+      }
+    });
+
+    it("should reject proofs against a replaced root", async () => {
+      const stale = buildSnapshot(discountSale.salePda, holdings);
+      const corrected = [...holdings.slice(0, 2), { wallet: web3.Keypair.generate().publicKey, balance: tokens(300) }];
+      await setSnapshotRoot(buildSnapshot(discountSale.salePda, corrected).root);
+
+      try {
+        await purchaseWithSnapshot(whale, stale.proofFor(whale.publicKey));
+        assert.fail("Should have failed for a stale root");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Snapshot proof does not match");
+      }
+    });
+  });
+
+  describe("Halt and Report", () => {
+    let haltedSale: StandaloneSale;
+
+    const haltAndReport = async () => {
+      const signature = await program.methods
+        .haltAndReport()
+        .accounts({
+          sale: haltedSale.salePda,
+          authority: haltedSale.authority.publicKey,
+          tokenMint: haltedSale.mint,
+          saleTokenVault: haltedSale.vault,
+          treasury: findTreasuryPda(haltedSale.salePda),
+        })
+        .signers([haltedSale.authority])
+        .rpc({ commitment: "confirmed" });
+
+      const tx = await connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(
+          tx.meta.logMessages
+        ),
+      ];
+    };
+
+    before(async () => {
+      haltedSale = await createFundedSale();
+      const buyer = await fundedWallet();
+      await program.methods
+        .purchaseTokens(tokens(200), false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(haltedSale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+    });
+
+    it("should pause the sale and report its post-pause state", async () => {
+      const events = await haltAndReport();
+      assert.ok(events.some(event => event.name === "saleToggled"));
+
+      const report = events.find(event => event.name === "saleHalted").data;
+      assert.isFalse(report.wasPaused);
+      assert.isTrue(report.isPaused);
+      assert.equal(report.tokensSold.toString(), tokens(200).toString());
+      assert.equal(report.totalRaised.toString(), costOf(tokens(200)).toString());
+      assert.equal(report.treasuryBalance.toString(), costOf(tokens(200)).toString());
+
+      const vault = await getAccount(connection, haltedSale.vault);
+      assert.equal(report.vaultBalance.toString(), vault.amount.toString());
+
+      const saleAccount = await program.account.sale.fetch(haltedSale.salePda);
+      assert.isTrue(saleAccount.isPaused);
+    });
+
+    it("should keep the sale paused when called again", async () => {
+      const events = await haltAndReport();
+      assert.isFalse(events.some(event => event.name === "saleToggled"));
+
+      const report = events.find(event => event.name === "saleHalted").data;
+      assert.isTrue(report.wasPaused);
+      assert.isTrue(report.isPaused);
+
+      const buyer = await fundedWallet();
+      try {
+        await program.methods
+          .purchaseTokens(MIN_PURCHASE, false, [], null, NO_DEADLINE, null)
+          .accounts(solPurchaseAccounts(haltedSale, buyer.publicKey))
+          .signers([buyer])
+          .rpc();
+        assert.fail("Should have failed while halted");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Sale is paused");
+      }
+    });
+  });
+
+  describe("Lot Size", () => {
+    const LOT_SIZE = tokens(100);
+
+    let lotSale: StandaloneSale;
+
+    before(async () => {
+      lotSale = await createFundedSale({ lotSize: LOT_SIZE });
+    });
+
+    it("should reject purchase limits that are not whole lots", async () => {
+      try {
+        await createFundedSale({ lotSize: LOT_SIZE, minPurchase: tokens(150) });
+        assert.fail("Should have failed with min_purchase off a lot boundary");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("multiple of the sale's lot size");
+      }
+
+      try {
+        await createFundedSale({ lotSize: LOT_SIZE, maxPurchase: tokens(950) });
+        assert.fail("Should have failed with max_purchase off a lot boundary");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("multiple of the sale's lot size");
+      }
+    });
+
+    it("should reject an allowlist cap that is not whole lots", async () => {
+      const sale = await createFundedSale({ lotSize: LOT_SIZE, startDelay: new BN(3600) });
+      const { startTime } = await program.account.sale.fetch(sale.salePda);
+      const setAllowlist = (maxPurchase: BN) =>
+        program.methods
+          .setAllowlist(Array(32).fill(1), startTime.addn(60), maxPurchase)
+          .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+          .signers([sale.authority])
+          .rpc();
+
+      try {
+        await setAllowlist(tokens(150));
+        assert.fail("Should have failed with whitelist_max_purchase off a lot boundary");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("multiple of the sale's lot size");
+      }
+
+      await setAllowlist(tokens(200));
+      const saleAccount = await program.account.sale.fetch(sale.salePda);
+      assert.equal(saleAccount.whitelistMaxPurchase.toString(), tokens(200).toString());
+    });
+
+    it("should reject purchases that are not whole lots", async () => {
+      const buyer = await fundedWallet();
+      try {
+        await program.methods
+          .purchaseTokens(tokens(250), false, [], null, NO_DEADLINE, null)
+          .accounts(solPurchaseAccounts(lotSale, buyer.publicKey))
+          .signers([buyer])
+          .rpc();
+        assert.fail("Should have failed for a partial lot");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("multiple of the sale's lot size");
+      }
+
+      await program.methods
+        .purchaseTokens(tokens(200), false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(lotSale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+      const purchase = await program.account.userPurchase.fetch(
+        solPurchaseAccounts(lotSale, buyer.publicKey).userPurchase
+      );
+      assert.equal(purchase.tokensPurchased.toString(), tokens(200).toString());
+    });
+
+    it("should round SOL-denominated purchases down to whole lots", async () => {
+      const buyer = await fundedWallet();
+      const treasury = findTreasuryPda(lotSale.salePda);
+      const treasuryBefore = await connection.getBalance(treasury);
+
+      // Enough SOL for 250 tokens buys two lots, and only those are charged
+      await program.methods
+        .purchaseWithSol(costOf(tokens(250)), tokens(200), [])
+        .accounts(solPurchaseAccounts(lotSale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+
+      const balance = await connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(lotSale.mint, buyer.publicKey)
+      );
+      assert.equal(balance.value.amount, tokens(200).toString());
+      const charged = (await connection.getBalance(treasury)) - treasuryBefore;
+      assert.equal(charged.toString(), costOf(tokens(200)).toString());
+    });
+  });
+
+  describe("Quote Currency", () => {
+    // Zero-padded symbol bytes back to a string
+    const symbolOf = (bytes: number[]) => Buffer.from(bytes).toString("utf8").replace(/\0+$/, "");
+
+    const initializedEvent = async (sale: StandaloneSale) => {
+      // The sale PDA's oldest transaction is its initialization
+      const signatures = await connection.getSignaturesForAddress(
+        sale.salePda,
+        {},
+        "confirmed"
+      );
+      const tx = await connection.getTransaction(signatures[signatures.length - 1].signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(
+          tx.meta.logMessages
+        ),
+      ].find(event => event.name === "saleInitialized").data;
+    };
+
+    const userInfo = (sale: StandaloneSale) =>
+      program.methods
+        .getUserInfo()
+        .accounts({
+          sale: sale.salePda,
+          userPurchase: null,
+          user: web3.Keypair.generate().publicKey,
+        })
+        .view();
+
+    it("should default to USD with 8 decimals", async () => {
+      const sale = await createFundedSale();
+
+      const initialized = await initializedEvent(sale);
+      assert.equal(symbolOf(initialized.quoteSymbol), "USD");
+      assert.equal(initialized.quoteDecimals, 8);
+
+      const info = await userInfo(sale);
+      assert.equal(symbolOf(info.quoteSymbol), "USD");
+      assert.equal(info.quoteDecimals, 8);
+    });
+
+    it("should carry a custom quote currency through the event and view", async () => {
+      const sale = await createFundedSale({ quoteSymbol: "EURC", quoteDecimals: 6 });
+
+      const initialized = await initializedEvent(sale);
+      assert.equal(symbolOf(initialized.quoteSymbol), "EURC");
+      assert.equal(initialized.quoteDecimals, 6);
+
+      const saleAccount = await program.account.sale.fetch(sale.salePda);
+      assert.equal(symbolOf(saleAccount.quoteSymbol), "EURC");
+
+      const info = await userInfo(sale);
+      assert.equal(symbolOf(info.quoteSymbol), "EURC");
+      assert.equal(info.quoteDecimals, 6);
+    });
+
+    it("should reject symbols over 8 bytes and too many decimals", async () => {
+      const invalid: SaleConfig[] = [
+        { quoteSymbol: "STABLECOIN" },
+        { quoteSymbol: "" },
+        { quoteDecimals: 19 },
+      ];
+      for (const config of invalid) {
+        try {
+          await createFundedSale(config);
+          assert.fail("Should have failed with an invalid quote currency");
+        } catch (error) {
+          expect(error.error.errorMessage).to.include("Quote symbol must be 1-8 bytes");
+        }
+      }
+    });
+  });
+
+  describe("Batch Refunds", () => {
+    let vestingSale: StandaloneSale;
+    let selfServed: web3.Keypair;
+    let cranked: web3.Keypair[];
+
+    const buy = async (sale: StandaloneSale, amount: BN) => {
+      const buyer = await fundedWallet();
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+      return buyer;
+    };
+
+    const failSale = async (sale: StandaloneSale) => {
+      await program.methods
+        .endSale()
+        .accounts({ sale: sale.salePda, authority: sale.authority.publicKey })
+        .signers([sale.authority])
+        .rpc();
+      await program.methods.finalizeSale().accounts({ sale: sale.salePda }).rpc();
+    };
+
+    // Crank a batch and return its summary event
+    const crank = async (sale: StandaloneSale, pairs: [web3.PublicKey, web3.PublicKey][]) => {
+      const signature = await program.methods
+        .processRefundsBatch()
+        .accounts({ sale: sale.salePda, treasury: findTreasuryPda(sale.salePda) })
+        .remainingAccounts(
+          pairs.flatMap(([userPurchase, buyer]) => [
+            { pubkey: userPurchase, isSigner: false, isWritable: true },
+            { pubkey: buyer, isSigner: false, isWritable: true },
+          ])
+        )
+        .rpc({ commitment: "confirmed" });
+
+      const tx = await connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(
+          tx.meta.logMessages
+        ),
+      ].find(event => event.name === "refundBatchProcessed").data;
+    };
+
+    const pairOf = (sale: StandaloneSale, buyer: web3.Keypair): [web3.PublicKey, web3.PublicKey] => [
+      solPurchaseAccounts(sale, buyer.publicKey).userPurchase,
+      buyer.publicKey,
+    ];
+
+    before(async () => {
+      // Vesting keeps every purchased token in the vault until claims open
+      vestingSale = await createFundedSale({
+        softCap: tokens(900),
+        tgeBps: 0,
+        vestingSeconds: new BN(3600),
+      });
+      selfServed = await buy(vestingSale, MIN_PURCHASE);
+      cranked = [await buy(vestingSale, tokens(200)), await buy(vestingSale, tokens(300))];
+    });
+
+    it("should only run once the sale has failed", async () => {
+      try {
+        await crank(vestingSale, [pairOf(vestingSale, cranked[0])]);
+        assert.fail("Should have failed before finalization");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Refunds are only available for failed sales");
+      }
+    });
+
+    it("should refund a mixed batch and skip an already-refunded record", async () => {
+      await failSale(vestingSale);
+      await program.methods
+        .claimRefund()
+        .accounts({
+          sale: vestingSale.salePda,
+          userPurchase: solPurchaseAccounts(vestingSale, selfServed.publicKey).userPurchase,
+          buyer: selfServed.publicKey,
+          tokenMint: vestingSale.mint,
+          saleTokenVault: vestingSale.vault,
+          buyerTokenAccount: getAssociatedTokenAddressSync(vestingSale.mint, selfServed.publicKey),
+          treasury: findTreasuryPda(vestingSale.salePda),
+          stakePosition: null,
+          stakeTokenAccount: null,
+          aggregateLimit: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([selfServed])
+        .rpc();
+
+      const balancesBefore = await Promise.all(
+        cranked.map(buyer => connection.getBalance(buyer.publicKey))
+      );
+      const summary = await crank(vestingSale, [
+        pairOf(vestingSale, selfServed),
+        ...cranked.map(buyer => pairOf(vestingSale, buyer)),
+      ]);
+
+      assert.equal(summary.refunded, 2);
+      assert.equal(summary.alreadyRefunded, 1);
+      assert.equal(summary.needsBuyer, 0);
+      assert.equal(summary.unprocessed, 0);
+      assert.equal(
+        summary.lamportsRefunded.toString(),
+        costOf(tokens(200)).add(costOf(tokens(300))).toString()
+      );
+
+      const amounts = [tokens(200), tokens(300)];
+      for (const [index, buyer] of cranked.entries()) {
+        const gained = (await connection.getBalance(buyer.publicKey)) - balancesBefore[index];
+        assert.equal(gained.toString(), costOf(amounts[index]).toString());
+        const purchase = await program.account.userPurchase.fetch(
+          solPurchaseAccounts(vestingSale, buyer.publicKey).userPurchase
+        );
+        assert.isTrue(purchase.refunded);
+      }
+
+      const saleAccount = await program.account.sale.fetch(vestingSale.salePda);
+      assert.equal(saleAccount.tokensOwed.toString(), "0");
+    });
+
+    it("should be idempotent across repeated cranks", async () => {
+      const treasury = findTreasuryPda(vestingSale.salePda);
+      const treasuryBefore = await connection.getBalance(treasury);
+
+      const summary = await crank(
+        vestingSale,
+        cranked.map(buyer => pairOf(vestingSale, buyer))
+      );
+      assert.equal(summary.refunded, 0);
+      assert.equal(summary.alreadyRefunded, 2);
+      assert.equal(summary.lamportsRefunded.toString(), "0");
+      assert.equal(await connection.getBalance(treasury), treasuryBefore);
+    });
+
+    it("should leave delivered purchases to the buyer", async () => {
+      const instantSale = await createFundedSale({ softCap: tokens(900) });
+      const buyer = await buy(instantSale, MIN_PURCHASE);
+      await failSale(instantSale);
+
+      const summary = await crank(instantSale, [pairOf(instantSale, buyer)]);
+      assert.equal(summary.refunded, 0);
+      assert.equal(summary.needsBuyer, 1);
+
+      const purchase = await program.account.userPurchase.fetch(
+        solPurchaseAccounts(instantSale, buyer.publicKey).userPurchase
+      );
+      assert.isFalse(purchase.refunded);
+    });
+
+    it("should reject a purchase record paired with another wallet", async () => {
+      try {
+        await crank(vestingSale, [
+          [solPurchaseAccounts(vestingSale, cranked[0].publicKey).userPurchase, cranked[1].publicKey],
+        ]);
+        assert.fail("Should have failed with a mismatched pair");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Refund batch accounts must be");
+      }
+    });
+
+    it("should reject a read-only buyer account", async () => {
+      const [userPurchase, buyer] = pairOf(vestingSale, cranked[0]);
+      try {
+        await program.methods
+          .processRefundsBatch()
+          .accounts({ sale: vestingSale.salePda, treasury: findTreasuryPda(vestingSale.salePda) })
+          .remainingAccounts([
+            { pubkey: userPurchase, isSigner: false, isWritable: true },
+            { pubkey: buyer, isSigner: false, isWritable: false },
+          ])
+          .rpc();
+        assert.fail("Should have failed with a read-only buyer");
+      } catch (error) {
+        expect(error.error.errorMessage).to.include("Refund batch accounts must be");
+      }
+    });
+  });
+
+  describe("Parameter Validation", () => {
+    // Failed-check bits of InitSaleParams
+    const CHECK = {
+      PRICE: 1 << 0,
+      MAX_TOKENS: 1 << 1,
+      PURCHASE_LIMITS: 1 << 2,
+      DURATION: 1 << 3,
+      STAKE_CONFIG: 1 << 4,
+      SOFT_CAP: 1 << 5,
+      VESTING_SCHEDULE: 1 << 6,
+      REFERRAL: 1 << 7,
+      ANTI_BOT_DURATIONS: 1 << 8,
+      PER_TX_CAP: 1 << 9,
+      LOT_SIZE: 1 << 10,
+      SCHEDULE_OVERFLOW: 1 << 11,
+      QUOTE_CURRENCY: 1 << 12,
+      SALE_EXISTS: 1 << 13,
+      TREASURY_EXISTS: 1 << 14,
+    };
+
+    const validParams = () => ({
+      tokenPrice: TOKEN_PRICE,
+      maxTokens: MAX_TOKENS,
+      minPurchase: MIN_PURCHASE,
+      maxPurchase: MAX_PURCHASE,
+      saleDuration: SALE_DURATION,
+      startDelay: NO_START_DELAY,
+      stakeBonusBps: STAKE_BONUS_BPS,
+      stakeLockDuration: STAKE_LOCK_DURATION,
+      stakeMultiplierBps: STAKE_MULTIPLIER_BPS,
+      softCap: NO_SOFT_CAP,
+      tgeBps: INSTANT_DELIVERY_BPS,
+      cliffSeconds: NO_CLIFF,
+      vestingDurationSeconds: NO_VESTING,
+      referralBps: NO_REFERRAL_BONUS,
+      immutable: MUTABLE_SALE,
+      maxPurchasePerTx: NO_TX_CAP,
+      purchaseCooldownSeconds: NO_COOLDOWN,
+      launchGuardSeconds: NO_LAUNCH_GUARD,
+      lotSize: NO_LOT_SIZE,
+      quoteSymbol: DEFAULT_QUOTE_SYMBOL,
+      quoteDecimals: DEFAULT_QUOTE_DECIMALS,
+      rounding: DEFAULT_ROUNDING as object | null,
+    });
+
+    let existingSale: StandaloneSale;
+
+    const validate = (
+      overrides: Partial<ReturnType<typeof validParams>>,
+      authority: web3.PublicKey = web3.Keypair.generate().publicKey
+    ) => {
+      const [salePda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("sale"), authority.toBuffer(), existingSale.mint.toBuffer()],
+        program.programId
+      );
+      return program.methods
+        .validateSaleParams({ ...validParams(), ...overrides })
+        .accounts({
+          sale: salePda,
+          authority,
+          tokenMint: existingSale.mint,
+          treasury: findTreasuryPda(salePda),
+        })
+        .view();
+    };
+
+    before(async () => {
+      existingSale = await createFundedSale();
+    });
+
+    it("should report no failures for valid parameters", async () => {
+      assert.equal(await validate({}), 0);
+    });
+
+    it("should report every failed bound, not just the first", async () => {
+      assert.equal(
+        await validate({ tokenPrice: new BN(0), maxTokens: new BN(0) }),
+        CHECK.PRICE | CHECK.MAX_TOKENS
+      );
+      assert.equal(
+        await validate({
+          minPurchase: tokens(500),
+          maxPurchase: tokens(100),
+          lotSize: tokens(300),
+        }),
+        CHECK.PURCHASE_LIMITS | CHECK.LOT_SIZE
+      );
+      assert.equal(
+        await validate({
+          tgeBps: 5_000,
+          referralBps: 2_000,
+          purchaseCooldownSeconds: new BN(-1),
+          quoteSymbol: "STABLECOIN",
+        }),
+        CHECK.VESTING_SCHEDULE | CHECK.REFERRAL | CHECK.ANTI_BOT_DURATIONS | CHECK.QUOTE_CURRENCY
+      );
+    });
+
+    it("should pre-check the schedule for overflow", async () => {
+      assert.equal(
+        await validate({ startDelay: new BN("9223372036854775807") }),
+        CHECK.SCHEDULE_OVERFLOW
+      );
+    });
+
+    it("should report accounts initialize_sale cannot create", async () => {
+      assert.equal(
+        await validate({}, existingSale.authority.publicKey),
+        CHECK.SALE_EXISTS | CHECK.TREASURY_EXISTS
+      );
+    });
+
+    it("should fail initialize_sale on the first check the dry run reports", async () => {
+      try {
+        await createFundedSale({ price: new BN(0), supply: new BN(0) });
+        assert.fail("Should have failed with an invalid price");
+      } catch (error) {
+        expect(error.error.errorMessage).to.equal("Invalid price");
+      }
+    });
+  });
+
+  describe("Rounding Policy", () => {
+    // At 0.001 SOL per token, 1000 base units cost exactly one lamport, so 1500
+    // extra base units cost x.5 lamports with an odd whole part and 2500 an even one
+    const ODD_HALF = MIN_PURCHASE.addn(1_500); // 100_000_001.5 lamports
+    const EVEN_HALF = MIN_PURCHASE.addn(2_500); // 100_000_002.5 lamports
+
+    const charged = async (sale: StandaloneSale, amount: BN) => {
+      const treasury = findTreasuryPda(sale.salePda);
+      const before = await connection.getBalance(treasury);
+      const buyer = await fundedWallet();
+      await program.methods
+        .purchaseTokens(amount, false, [], null, NO_DEADLINE, null)
+        .accounts(solPurchaseAccounts(sale, buyer.publicKey))
+        .signers([buyer])
+        .rpc();
+      return (await connection.getBalance(treasury)) - before;
+    };
+
+    it("should keep rounding costs up by default", async () => {
+      const sale = await createFundedSale();
+      const saleAccount = await program.account.sale.fetch(sale.salePda);
+      assert.deepEqual(saleAccount.rounding, { ceilCost: {} });
+
+      assert.equal(await charged(sale, ODD_HALF), 100_000_002);
+      assert.equal(await charged(sale, EVEN_HALF), 100_000_003);
+    });
+
+    it("should round costs down with FloorCost", async () => {
+      const sale = await createFundedSale({ rounding: { floorCost: {} } });
+
+      assert.equal(await charged(sale, ODD_HALF), 100_000_001);
+      assert.equal(await charged(sale, EVEN_HALF), 100_000_002);
+    });
+
+    it("should round half-way costs to even with HalfEven", async () => {
+      const sale = await createFundedSale({ rounding: { halfEven: {} } });
+
+      assert.equal(await charged(sale, ODD_HALF), 100_000_002);
+      assert.equal(await charged(sale, EVEN_HALF), 100_000_002);
+      // Not a tie: 100_000_000.9 lamports
+      assert.equal(await charged(sale, MIN_PURCHASE.addn(900)), 100_000_001);
+    });
+
+    it("should reject purchases that round down to no cost", async () => {
+      // One base unit costs 0.001 lamports, which FloorCost and HalfEven round to 0
+      for (const rounding of [{ floorCost: {} }, { halfEven: {} }]) {
+        const sale = await createFundedSale({ rounding, minPurchase: new BN(1) });
+        try {
+          await charged(sale, new BN(1));
+          assert.fail("Should have failed on a zero cost");
+        } catch (error) {
+          expect(error.error.errorMessage).to.include("Purchase amount is too small to cost anything");
+        }
+      }
+
+      const ceilSale = await createFundedSale({ minPurchase: new BN(1) });
+      assert.equal(await charged(ceilSale, new BN(1)), 1);
+    });
+
+    it("should carry the policy in SaleInitialized", async () => {
+      const sale = await createFundedSale({ rounding: { halfEven: {} } });
+      const signatures = await connection.getSignaturesForAddress(
+        sale.salePda,
+        {},
+        "confirmed"
+      );
+      const tx = await connection.getTransaction(signatures[signatures.length - 1].signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const initialized = [
+        ...new anchor.EventParser(program.programId, program.coder).parseLogs(
+          tx.meta.logMessages
+        ),
+      ].find(event => event.name === "saleInitialized").data;
+      assert.deepEqual(initialized.rounding, { halfEven: {} });
+    });
+  });
+});